@@ -31,7 +31,7 @@ pub async fn server() -> anyhow::Result<(), hyper::Error> {
                 _ => {
                     let body = hyper::body::to_bytes(req.into_body()).await?;
                     let body_s = String::from_utf8_lossy(&body);
-                    if body_s.len() > 0 {
+                    if !body_s.is_empty() {
                         log::info!("Body:\n---\n{}\n---\n", body_s);
                     }
 
@@ -0,0 +1,94 @@
+#!/usr/bin/env python3
+
+# A mock identity server to try email invites locally.
+#
+# Accepts every registration and stores invites in memory, printing each one.
+#
+# The homeserver always reaches the identity server over HTTPS, at the hostname and port the bot passes on.
+# So serve TLS with a certificate both the bot and the homeserver trust, e.g. one made with mkcert:
+#
+#     mkcert -install && mkcert localhost
+#     scripts/mock-identity-server.py 8090 localhost.pem localhost-key.pem
+#
+# and point the bot at it with `identity_server = "localhost:8090"` in `[invites]`.
+# Without a certificate the server speaks plain HTTP, which is only enough to try the bot's registration
+# with `identity_server = "http://localhost:8090"`: the homeserver will fail to store the invites.
+#
+# Usage: scripts/mock-identity-server.py [port [cert key]]
+
+import json
+import secrets
+import ssl
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+PUBLIC_KEY = "ta8IQ0u1sp44HVpxYi7dFOdS/bfwDjcy4xLFlfY5KOA"
+invites = []
+scheme = "http"
+
+
+class Handler(BaseHTTPRequestHandler):
+    def reply(self, status, body):
+        data = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def body(self):
+        length = int(self.headers.get("Content-Length", 0))
+        return json.loads(self.rfile.read(length) or b"{}")
+
+    def do_GET(self):
+        if self.path == "/_matrix/identity/v2":
+            self.reply(200, {})
+        elif self.path == "/_matrix/identity/v2/terms":
+            self.reply(200, {"policies": {}})
+        elif self.path.startswith("/_matrix/identity/v2/pubkey/isvalid"):
+            self.reply(200, {"valid": True})
+        elif self.path.startswith("/_matrix/identity/v2/pubkey/"):
+            self.reply(200, {"public_key": PUBLIC_KEY})
+        else:
+            self.reply(404, {"errcode": "M_NOT_FOUND", "error": "Not found"})
+
+    def do_POST(self):
+        if self.path == "/_matrix/identity/v2/account/register":
+            body = self.body()
+            print(f"Registered {body.get('matrix_server_name')}", flush=True)
+            self.reply(200, {"token": secrets.token_urlsafe(16)})
+        elif self.path == "/_matrix/identity/v2/terms":
+            self.reply(200, {})
+        elif self.path == "/_matrix/identity/v2/store-invite":
+            body = self.body()
+            invites.append(body)
+            print(f"Invite for {body.get('address')} to {body.get('room_id')}", flush=True)
+            address = body.get("address", "")
+            self.reply(
+                200,
+                {
+                    "token": secrets.token_urlsafe(16),
+                    "public_key": PUBLIC_KEY,
+                    "public_keys": [
+                        {
+                            "public_key": PUBLIC_KEY,
+                            "key_validity_url": f"{scheme}://{self.headers['Host']}/_matrix/identity/v2/pubkey/isvalid",
+                        }
+                    ],
+                    "display_name": address[:3] + "...",
+                },
+            )
+        else:
+            self.reply(404, {"errcode": "M_NOT_FOUND", "error": "Not found"})
+
+
+if __name__ == "__main__":
+    port = int(sys.argv[1]) if len(sys.argv) > 1 else 8090
+    server = HTTPServer(("", port), Handler)
+    if len(sys.argv) > 3:
+        context = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+        context.load_cert_chain(sys.argv[2], sys.argv[3])
+        server.socket = context.wrap_socket(server.socket, server_side=True)
+        scheme = "https"
+    print(f"Mock identity server listening on port {port} over {scheme.upper()}", flush=True)
+    server.serve_forever()
@@ -0,0 +1,534 @@
+//! Scheduled announcements.
+//!
+//! Announcements are kept in the state store and sent to their room once they are due,
+//! either once at a given time or repeatedly following a cron-like recurrence.
+
+use crate::{
+    audit::{AuditLog, Record},
+    matrix,
+    store::Store,
+};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc};
+use ruma::RoomId;
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+use serde::{Deserialize, Serialize};
+
+/// How often to check for due announcements.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A scheduled announcement.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    /// The announcement's ID, used to cancel it.
+    pub id: u64,
+    /// The room to send the announcement to.
+    pub room_id: RoomId,
+    /// The message to send.
+    pub message: String,
+    /// When the announcement is sent next.
+    pub at: DateTime<Utc>,
+    /// When to repeat the announcement, if at all.
+    pub repeat: Option<Recurrence>,
+    /// Who scheduled the announcement.
+    pub created_by: String,
+}
+
+/// How far ahead relative times can be, in minutes.
+const MAX_RELATIVE_MINUTES: u32 = 366 * 24 * 60;
+
+/// When to send an announcement.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum When {
+    /// Once, at the given time.
+    At(DateTime<Utc>),
+    /// Repeatedly.
+    Cron(Recurrence),
+}
+
+impl When {
+    /// Parse a time as used in commands.
+    ///
+    /// This is a UTC time in RFC 3339 format, e.g. `2021-06-19T12:00:00Z`,
+    /// or a time relative to now, e.g. `+10m`, `+2h` or `+1d`, up to a year ahead.
+    pub fn parse_time(time: &str) -> anyhow::Result<Self> {
+        if let Some(relative) = time.strip_prefix('+') {
+            let (amount, unit_minutes) = if let Some(amount) = relative.strip_suffix('m') {
+                (amount, 1)
+            } else if let Some(amount) = relative.strip_suffix('h') {
+                (amount, 60)
+            } else if let Some(amount) = relative.strip_suffix('d') {
+                (amount, 24 * 60)
+            } else {
+                bail!("unknown time unit in {}", time);
+            };
+            let minutes = amount
+                .parse::<u32>()
+                .map_err(|_| anyhow!("invalid amount of time in {}", time))?
+                .checked_mul(unit_minutes)
+                .filter(|minutes| *minutes <= MAX_RELATIVE_MINUTES)
+                .ok_or_else(|| anyhow!("{} is more than a year ahead", time))?;
+            let at = Utc::now()
+                .checked_add_signed(chrono::Duration::minutes(minutes.into()))
+                .ok_or_else(|| anyhow!("{} is out of range", time))?;
+            return Ok(When::At(at));
+        }
+
+        let at = DateTime::parse_from_rfc3339(time)?;
+        Ok(When::At(at.with_timezone(&Utc)))
+    }
+}
+
+/// A cron-like recurrence: minute, hour, day of month, month and day of week.
+///
+/// Each field is `*`, a number, a range like `1-5`, a step like `*/15` or `10-20/5`,
+/// or a comma-separated list of these. All times are in UTC.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct Recurrence {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month or the day of week is restricted.
+    /// If both are, either has to match.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl TryFrom<String> for Recurrence {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: String) -> Result<Self, Self::Error> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        let (minute, hour, day, month, weekday) = match fields[..] {
+            [minute, hour, day, month, weekday] => (minute, hour, day, month, weekday),
+            _ => bail!("a recurrence needs 5 fields: minute hour day month weekday"),
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Recurrence {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            days_restricted: day != "*",
+            weekdays_restricted: weekday != "*",
+            expr: fields.join(" "),
+        })
+    }
+}
+
+impl From<Recurrence> for String {
+    fn from(recurrence: Recurrence) -> String {
+        recurrence.expr
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+/// Parse a single field of a recurrence into a bit set of the matching values.
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>()?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None if step > 1 => (range.parse()?, max),
+            None => (range.parse()?, range.parse()?),
+        };
+        if step == 0 || start < min || end > max || start > end {
+            bail!("invalid recurrence field {}", field);
+        }
+        for value in (start..=end).step_by(step) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl Recurrence {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time matching the recurrence after the given time.
+    ///
+    /// Returns `None` if there is none within the next 5 years, e.g. for February 31st.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let mut t = start.naive_utc();
+        let limit = t + chrono::Duration::days(5 * 366);
+
+        while t < limit {
+            let date = t.date();
+            if self.months & (1 << date.month()) == 0 {
+                let (year, month) = match date.month() {
+                    12 => (date.year() + 1, 1),
+                    month => (date.year(), month + 1),
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(date) {
+                t = (date + chrono::Duration::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + chrono::Duration::hours(1);
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += chrono::Duration::minutes(1);
+            } else {
+                return Some(Utc.from_utc_datetime(&t));
+            }
+        }
+        None
+    }
+}
+
+/// Schedule a new announcement.
+///
+/// Fails if a one-off announcement is due in the past, as it would never be sent.
+pub async fn add(
+    store: &Store,
+    audit: &AuditLog,
+    actor: &str,
+    room_id: RoomId,
+    message: String,
+    when: When,
+) -> anyhow::Result<Announcement> {
+    let (at, repeat) = match when {
+        When::At(at) if at <= Utc::now() => {
+            bail!("{} is in the past", at.format("%Y-%m-%d %H:%M UTC"))
+        }
+        When::At(at) => (at, None),
+        When::Cron(recurrence) => {
+            let at = recurrence
+                .next_after(Utc::now())
+                .ok_or_else(|| anyhow!("the recurrence {} never matches", recurrence))?;
+            (at, Some(recurrence))
+        }
+    };
+
+    let announcement = store.update(|data| {
+        data.next_announcement_id += 1;
+        let announcement = Announcement {
+            id: data.next_announcement_id,
+            room_id,
+            message,
+            at,
+            repeat,
+            created_by: actor.to_string(),
+        };
+        data.announcements
+            .insert(announcement.id, announcement.clone());
+        announcement
+    })?;
+
+    let record = Record::new(actor, "schedule")
+        .target(format!("announcement {}", announcement.id))
+        .room(&announcement.room_id)
+        .reason(Some(&announcement.message));
+    audit.record(record).await;
+
+    Ok(announcement)
+}
+
+/// List all scheduled announcements, the next one first.
+pub fn list(store: &Store) -> Vec<Announcement> {
+    let mut announcements =
+        store.read(|data| data.announcements.values().cloned().collect::<Vec<_>>());
+    announcements.sort_by_key(|announcement| announcement.at);
+    announcements
+}
+
+/// Cancel a scheduled announcement.
+///
+/// Returns the cancelled announcement, or `None` if there is no announcement with that ID.
+pub async fn cancel(
+    store: &Store,
+    audit: &AuditLog,
+    actor: &str,
+    id: u64,
+) -> anyhow::Result<Option<Announcement>> {
+    let announcement = store.update(|data| data.announcements.remove(&id))?;
+
+    if let Some(announcement) = &announcement {
+        let record = Record::new(actor, "cancel-schedule")
+            .target(format!("announcement {}", announcement.id))
+            .room(&announcement.room_id);
+        audit.record(record).await;
+    }
+
+    Ok(announcement)
+}
+
+/// Send announcements once they are due.
+///
+/// Runs forever.
+/// Announcements more than `grace` late, e.g. because the bot was not running, are not sent.
+/// One-time announcements are dropped then, repeated announcements wait for their next time.
+pub async fn run(client: Client, store: Store, grace: Duration) -> anyhow::Result<()> {
+    let grace = chrono::Duration::from_std(grace)?;
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now();
+        let due = store.read(|data| {
+            data.announcements
+                .values()
+                .filter(|announcement| announcement.at <= now)
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+
+        for announcement in due {
+            if now - announcement.at > grace {
+                log::warn!(
+                    "Skipping announcement {} in {}, it was due at {}",
+                    announcement.id,
+                    announcement.room_id,
+                    announcement.at
+                );
+            } else if let Err(e) =
+                matrix::send_message(&client, &announcement.room_id, &announcement.message[..])
+                    .await
+            {
+                log::error!(
+                    "Failed to send announcement {}. Error: {:?}",
+                    announcement.id,
+                    e
+                );
+            }
+
+            let next = announcement
+                .repeat
+                .as_ref()
+                .and_then(|recurrence| recurrence.next_after(now));
+            let updated = store.update(|data| match next {
+                Some(next) => {
+                    if let Some(announcement) = data.announcements.get_mut(&announcement.id) {
+                        announcement.at = next;
+                    }
+                }
+                None => {
+                    data.announcements.remove(&announcement.id);
+                }
+            });
+            if let Err(e) = updated {
+                log::error!(
+                    "Failed to update announcement {}. Error: {:?}",
+                    announcement.id,
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn parse_at(time: &str) -> DateTime<Utc> {
+        match When::parse_time(time).unwrap() {
+            When::At(at) => at,
+            When::Cron(recurrence) => panic!("unexpected recurrence {}", recurrence),
+        }
+    }
+
+    fn recurrence(expr: &str) -> Recurrence {
+        Recurrence::try_from(expr.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parse_absolute_time() {
+        assert_eq!(parse_at("2021-06-19T12:00:00Z"), at("2021-06-19T12:00:00Z"));
+        assert_eq!(
+            parse_at("2021-06-19T14:00:00+02:00"),
+            at("2021-06-19T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn parse_relative_time() {
+        let before = Utc::now();
+        let parsed = parse_at("+10m");
+        assert!(parsed >= before + chrono::Duration::minutes(10));
+        assert!(parsed <= Utc::now() + chrono::Duration::minutes(10));
+
+        let parsed = parse_at("+2h");
+        assert!(parsed >= before + chrono::Duration::hours(2));
+        let parsed = parse_at("+1d");
+        assert!(parsed >= before + chrono::Duration::days(1));
+        assert!(When::parse_time("+366d").is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_relative_time() {
+        for time in &[
+            "+",
+            "+m",
+            "+10",
+            "+10x",
+            "+10é",
+            "+é",
+            "+-5m",
+            "+1.5h",
+            "+367d",
+            "+9999999999d",
+        ] {
+            assert!(When::parse_time(time).is_err(), "{} should not parse", time);
+        }
+        assert!(When::parse_time("tomorrow").is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_announcement_in_the_past() {
+        let store = Store::load(None).unwrap();
+        let client = crate::RumaClient::new("http://localhost".into(), None);
+        let audit = AuditLog::new(client, None, None);
+        let room_id = RoomId::try_from("!room:example.com").unwrap();
+
+        let past = When::At(Utc::now() - chrono::Duration::minutes(1));
+        let res = add(&store, &audit, "test", room_id.clone(), "hi".into(), past).await;
+        assert!(res.is_err());
+        assert!(list(&store).is_empty());
+
+        let future = When::parse_time("+10m").unwrap();
+        let announcement = add(&store, &audit, "test", room_id, "hi".into(), future)
+            .await
+            .unwrap();
+        assert_eq!(list(&store).len(), 1);
+        assert_eq!(list(&store)[0].id, announcement.id);
+    }
+
+    #[test]
+    fn parse_recurrence() {
+        let r = recurrence("*/15 9-17 * * 1-5");
+        assert_eq!(r.to_string(), "*/15 9-17 * * 1-5");
+        assert_eq!(r.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(r.hours, (9..=17).fold(0, |set, h| set | 1 << h));
+        assert_eq!(r.weekdays, 0b111110);
+        assert!(!r.days_restricted);
+        assert!(r.weekdays_restricted);
+
+        assert_eq!(recurrence("0,30 * * * *").minutes, 1 | 1 << 30);
+        assert_eq!(
+            recurrence("10-20/5 * * * *").minutes,
+            1 << 10 | 1 << 15 | 1 << 20
+        );
+        assert_eq!(
+            recurrence("5/20 * * * *").minutes,
+            1 << 5 | 1 << 25 | 1 << 45
+        );
+        // 7 is Sunday, like 0.
+        assert_eq!(recurrence("0 0 * * 7").weekdays, 1 | 1 << 7);
+        // Extra whitespace is normalized.
+        assert_eq!(recurrence(" 0  12 * * * ").to_string(), "0 12 * * *");
+    }
+
+    #[test]
+    fn reject_invalid_recurrence() {
+        for expr in &[
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "20-10 * * * *",
+            "5- * * * *",
+            "a * * * *",
+            "é * * * *",
+        ] {
+            assert!(
+                Recurrence::try_from(expr.to_string()).is_err(),
+                "{:?} should not parse",
+                expr
+            );
+        }
+    }
+
+    #[test]
+    fn next_recurrence() {
+        let r = recurrence("*/15 * * * *");
+        assert_eq!(
+            r.next_after(at("2021-06-19T12:07:30Z")),
+            Some(at("2021-06-19T12:15:00Z"))
+        );
+        assert_eq!(
+            r.next_after(at("2021-06-19T12:15:00Z")),
+            Some(at("2021-06-19T12:30:00Z"))
+        );
+
+        // 2021-06-19 is a Saturday.
+        let r = recurrence("0 9 * * 1-5");
+        assert_eq!(
+            r.next_after(at("2021-06-19T12:00:00Z")),
+            Some(at("2021-06-21T09:00:00Z"))
+        );
+
+        let r = recurrence("30 23 31 12 *");
+        assert_eq!(
+            r.next_after(at("2021-06-19T12:00:00Z")),
+            Some(at("2021-12-31T23:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn next_recurrence_day_or_weekday() {
+        // With both restricted, either the day of month or the day of week matches.
+        let r = recurrence("0 12 1 * 1");
+        // Monday, June 21st.
+        assert_eq!(
+            r.next_after(at("2021-06-19T12:00:00Z")),
+            Some(at("2021-06-21T12:00:00Z"))
+        );
+        // Thursday, July 1st, before the next Monday.
+        assert_eq!(
+            r.next_after(at("2021-06-28T12:00:00Z")),
+            Some(at("2021-07-01T12:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn recurrence_never_matching() {
+        assert_eq!(
+            recurrence("0 0 31 2 *").next_after(at("2021-06-19T12:00:00Z")),
+            None
+        );
+    }
+}
@@ -2,42 +2,119 @@
 //!
 //! This serves a simple API over HTTP.
 //!
-//! It implements 2 endpoints:
+//! It implements 17 endpoints:
 //!
-//! * `POST /invite` - Invite a user to a channel.
-//! * `POST /room` - Create a new room.
+//! * `POST /invite` - Invite a user or an email address to a channel.
+//! * `POST /invite/bulk` - Start inviting many users to many channels.
+//! * `POST /invite/bulk/status` - Show the progress of a bulk invite.
+//! * `POST /room` - Create a new room or space.
+//! * `POST /space/add` - Add a room to a space.
+//! * `POST /space/remove` - Remove a room from a space.
+//! * `POST /moderate` - Ban, kick or mute a user in all rooms.
+//! * `POST /acl` - Show or change the server ACL of all rooms.
+//! * `POST /lock` - Make a room read-only.
+//! * `POST /unlock` - Restore the permissions of a locked room.
+//! * `POST /power` - Change the power levels of a room.
+//! * `POST /provision` - Reconcile the rooms with a conference manifest.
+//! * `POST /announcements` - Schedule an announcement.
+//! * `POST /announcements/list` - List the scheduled announcements.
+//! * `POST /announcements/cancel` - Cancel a scheduled announcement.
+//! * `POST /leave` - Make the bot leave a room.
+//! * `POST /upgrade` - Upgrade a room to a new room version.
 
-use super::matrix;
-use std::{convert::TryFrom, net::SocketAddr, sync::Arc};
+use super::{
+    announcements::{self, When},
+    audit::{AuditLog, Record},
+    bot::ManagedRooms,
+    config::{self, RoomTemplate},
+    invites::{InviteQueue, Invitee, Status},
+    matrix::{self, PowerLevelChange},
+    moderation::{self, AclChange, Action},
+    provision::{self, Manifest},
+    store::Store,
+    upgrade,
+};
+use std::{collections::BTreeMap, convert::TryFrom, fmt, net::SocketAddr, sync::Arc};
 
 use hyper::{
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, StatusCode,
 };
 use ruma::UserId;
-use ruma_client;
 type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
 use serde::Deserialize;
+use serde_json::json;
 
 struct Config {
     client: Client,
+    bot_id: UserId,
     admin_users: Vec<String>,
     api_secret: String,
+    api_tokens: BTreeMap<String, String>,
+    audit: AuditLog,
+    store: Store,
+    templates: BTreeMap<String, RoomTemplate>,
+    invites: InviteQueue,
+    managed: ManagedRooms,
+}
+
+impl Config {
+    /// Check the API key of a request.
+    ///
+    /// Returns the actor to record in the audit trail if the key is valid.
+    /// Returns `None` otherwise.
+    fn authorize(&self, api_key: &ApiKey) -> Option<String> {
+        if api_key.0 == self.api_secret {
+            return Some("api:default".into());
+        }
+        self.api_tokens
+            .iter()
+            .find(|(_, secret)| **secret == api_key.0)
+            .map(|(name, _)| format!("api:{}", name))
+    }
+}
+
+/// The API key sent with a request.
+///
+/// Requests are logged, so it never shows up in debug output.
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct ApiKey(String);
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
 }
 
 static INDEX_PAGE: &str = include_str!("../../index.html");
 
 /// Start up a server to handle API requests
+#[allow(clippy::too_many_arguments)]
 pub async fn server(
     addr: SocketAddr,
     api_secret: String,
+    api_tokens: BTreeMap<String, String>,
+    bot_id: UserId,
     admin_users: Vec<String>,
     client: Client,
+    audit: AuditLog,
+    store: Store,
+    templates: BTreeMap<String, RoomTemplate>,
+    invites: InviteQueue,
+    managed: ManagedRooms,
 ) -> anyhow::Result<(), hyper::Error> {
     let config = Arc::new(Config {
         client,
+        bot_id,
         admin_users,
         api_secret,
+        api_tokens,
+        audit,
+        store,
+        templates,
+        invites,
+        managed,
     });
 
     let make_service = make_service_fn(move |_| {
@@ -62,6 +139,26 @@ pub async fn server(
                                 Ok::<_, hyper::Error>(response)
                             }
                         },
+                        (&Method::POST, "/invite/bulk") => match bulk_invite(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to invite users in bulk. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/invite/bulk/status") => {
+                            match bulk_invite_status(&config, req).await {
+                                Ok(resp) => Ok(resp),
+                                Err(e) => {
+                                    log::error!("Failed to show a bulk invite. Error: {:?}", e);
+                                    let mut response = Response::new(Body::empty());
+                                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                    Ok::<_, hyper::Error>(response)
+                                }
+                            }
+                        }
                         (&Method::POST, "/room") => match create_room(&config, req).await {
                             Ok(resp) => Ok(resp),
                             Err(e) => {
@@ -71,6 +168,132 @@ pub async fn server(
                                 Ok::<_, hyper::Error>(response)
                             }
                         },
+                        (&Method::POST, "/space/add") => match space(&config, req, true).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to add a room to a space. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/space/remove") => {
+                            match space(&config, req, false).await {
+                                Ok(resp) => Ok(resp),
+                                Err(e) => {
+                                    log::error!(
+                                        "Failed to remove a room from a space. Error: {:?}",
+                                        e
+                                    );
+                                    let mut response = Response::new(Body::empty());
+                                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                    Ok::<_, hyper::Error>(response)
+                                }
+                            }
+                        }
+                        (&Method::POST, "/moderate") => match moderate(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to moderate a user. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/acl") => match acl(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to update server ACLs. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/lock") => match lock(&config, req, true).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to lock a room. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/unlock") => match lock(&config, req, false).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to unlock a room. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/power") => match power(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to change power levels. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/provision") => match provision(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to provision rooms. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/announcements") => match schedule(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to schedule an announcement. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/announcements/list") => {
+                            match list_announcements(&config, req).await {
+                                Ok(resp) => Ok(resp),
+                                Err(e) => {
+                                    log::error!("Failed to list announcements. Error: {:?}", e);
+                                    let mut response = Response::new(Body::empty());
+                                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                    Ok::<_, hyper::Error>(response)
+                                }
+                            }
+                        }
+                        (&Method::POST, "/announcements/cancel") => {
+                            match cancel_announcement(&config, req).await {
+                                Ok(resp) => Ok(resp),
+                                Err(e) => {
+                                    log::error!("Failed to cancel an announcement. Error: {:?}", e);
+                                    let mut response = Response::new(Body::empty());
+                                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                    Ok::<_, hyper::Error>(response)
+                                }
+                            }
+                        }
+                        (&Method::POST, "/upgrade") => match upgrade_room(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to upgrade a room. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                        (&Method::POST, "/leave") => match leave(&config, req).await {
+                            Ok(resp) => Ok(resp),
+                            Err(e) => {
+                                log::error!("Failed to leave a room. Error: {:?}", e);
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
                         _ => {
                             let mut response = Response::new(Body::empty());
                             *response.status_mut() = StatusCode::NOT_FOUND;
@@ -90,16 +313,18 @@ pub async fn server(
 #[derive(Deserialize, Debug)]
 struct ApiInviteUser {
     /// The full user ID to invite.
-    user_id: String,
+    user_id: Option<String>,
+    /// The email address to invite instead of a user ID.
+    email: Option<String>,
     /// The room ID to invite the user into.
     room_id: String,
     /// The API key
-    api_key: String,
+    api_key: ApiKey,
 }
 
 /// POST /invite
 ///
-/// Handle invitation requests and invite the user to a channel.
+/// Handle invitation requests and invite the user or email address to a channel.
 async fn invite(
     config: &Config,
     request: Request<hyper::Body>,
@@ -108,31 +333,246 @@ async fn invite(
 
     let whole_body = hyper::body::to_bytes(request.into_body()).await?;
     let invitation: ApiInviteUser = serde_json::from_slice(&whole_body)?;
-    if invitation.api_key != config.api_secret {
-        *response.status_mut() = StatusCode::FORBIDDEN;
-        return Ok(response);
-    }
+    let actor = match config.authorize(&invitation.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
     log::info!("Received invite request: {:?}", invitation);
 
+    let (target, invitee) = match (&invitation.user_id, &invitation.email) {
+        (Some(user_id), None) => (user_id, Invitee::User(UserId::try_from(&user_id[..])?)),
+        (None, Some(email)) => (email, Invitee::Email(email.clone())),
+        _ => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(response);
+        }
+    };
     let room_id = matrix::real_room_id(&config.client, &invitation.room_id).await?;
-    matrix::invite_user(&config.client, &room_id, &invitation.user_id).await?;
+    let result = config
+        .invites
+        .invite_any(&config.client, &room_id, &invitee)
+        .await?;
+
+    if result == Status::Invited {
+        let record = Record::new(actor, "invite")
+            .target(target)
+            .room(&invitation.room_id);
+        config.audit.record(record).await;
+    }
+
+    let body = json!({"status": "ok", "result": result});
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Make the bot leave a room.
+#[derive(Deserialize, Debug)]
+struct ApiLeave {
+    /// The API key
+    api_key: ApiKey,
+    /// The room to leave.
+    room_id: String,
+}
+
+/// POST /leave
+///
+/// Leave a room. The bot forgets the room and its settings.
+async fn leave(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let leave: ApiLeave = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&leave.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received leave request: {:?}", leave);
+
+    let room_id = matrix::real_room_id(&config.client, &leave.room_id).await?;
+    matrix::leave_room(&config.client, &room_id).await?;
+
+    let record = Record::new(actor, "leave").room(&leave.room_id);
+    config.audit.record(record).await;
 
     *response.body_mut() = Body::from(r#"{"status": "ok" }"#);
 
     Ok(response)
 }
 
+/// Upgrade a room to a new room version.
+#[derive(Deserialize, Debug)]
+struct ApiUpgrade {
+    /// The API key
+    api_key: ApiKey,
+    /// The room to upgrade.
+    room_id: String,
+    /// The room version to upgrade to, the server's default version if not set.
+    version: Option<String>,
+}
+
+/// POST /upgrade
+///
+/// Upgrade a room and carry over the settings the bot manages.
+async fn upgrade_room(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let upgrade: ApiUpgrade = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&upgrade.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received upgrade request: {:?}", upgrade);
+
+    let room_id = matrix::real_room_id(&config.client, &upgrade.room_id).await?;
+    let upgraded = upgrade::upgrade(
+        &config.client,
+        &config.audit,
+        &actor,
+        &room_id,
+        upgrade.version.as_deref(),
+    )
+    .await?;
+
+    let body = json!({
+        "status": "ok",
+        "room_id": upgraded.room_id,
+        "version": upgraded.version,
+        "failed": upgraded.failed,
+    });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Invite many users to many rooms.
+#[derive(Deserialize, Debug)]
+struct ApiBulkInvite {
+    /// The API key
+    api_key: ApiKey,
+    /// The users to invite.
+    users: Vec<String>,
+    /// The rooms to invite each user to.
+    rooms: Vec<String>,
+}
+
+/// POST /invite/bulk
+///
+/// Start inviting each user to each room in the background.
+/// Responds with the job ID to follow the progress with `POST /invite/bulk/status`.
+async fn bulk_invite(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let invitation: ApiBulkInvite = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&invitation.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received bulk invite request: {:?}", invitation);
+
+    let total = invitation.users.len() * invitation.rooms.len();
+    let job_id = config.invites.start_bulk(
+        &config.client,
+        &config.audit,
+        &actor,
+        invitation.users,
+        invitation.rooms,
+    );
+
+    let body = json!({"status": "ok", "job_id": job_id, "total": total});
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Show the progress of a bulk invite.
+#[derive(Deserialize, Debug)]
+struct ApiBulkInviteStatus {
+    /// The API key
+    api_key: ApiKey,
+    /// The ID of the bulk invite.
+    job_id: u64,
+}
+
+/// POST /invite/bulk/status
+///
+/// Responds with the outcome for every user and room invited so far.
+async fn bulk_invite_status(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let status: ApiBulkInviteStatus = serde_json::from_slice(&whole_body)?;
+    if config.authorize(&status.api_key).is_none() {
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return Ok(response);
+    }
+
+    let job = match config.invites.bulk_job(status.job_id) {
+        Some(job) => job,
+        None => {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+    };
+
+    let body = json!({
+        "status": "ok",
+        "job_id": status.job_id,
+        "done": job.done,
+        "total": job.total,
+        "results": job.results,
+    });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
 /// Create a new room.
 #[derive(Deserialize, Debug)]
 struct ApiCreateRoom {
     /// The API key
-    api_key: String,
+    api_key: ApiKey,
     /// The room's alias.
     alias: String,
     /// The room's name.
     name: String,
     /// The optional topic for the room.
     topic: Option<String>,
+    /// The optional name of the room template to use.
+    template: Option<String>,
+    /// Create a space instead of a room.
+    #[serde(default)]
+    is_space: bool,
+    /// The optional space to create the room in.
+    space: Option<String>,
+    /// The optional position of the room within the space.
+    order: Option<String>,
 }
 
 /// POST /room
@@ -146,27 +586,511 @@ async fn create_room(
 
     let whole_body = hyper::body::to_bytes(request.into_body()).await?;
     let room: ApiCreateRoom = serde_json::from_slice(&whole_body)?;
-    if room.api_key != config.api_secret {
-        *response.status_mut() = StatusCode::FORBIDDEN;
-        return Ok(response);
-    }
+    let actor = match config.authorize(&room.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
     log::info!("Received create_room: {:?}", room);
 
+    let template = config::find_template(&config.templates, room.template.as_deref())?;
+
     let invite = config
         .admin_users
         .iter()
         .map(|user| UserId::try_from(&user[..]).unwrap())
         .collect::<Vec<_>>();
-    matrix::create_room(
+    let room_id = matrix::create_room(
         &config.client,
         &room.alias,
         &room.name,
         room.topic.as_deref(),
         &invite,
+        &template,
+        room.is_space,
     )
     .await?;
+    let template_name = room.template.as_deref().unwrap_or(config::DEFAULT_TEMPLATE);
+    config.store.update(|data| {
+        data.room_templates
+            .insert(room_id.clone(), template_name.to_string())
+    })?;
+
+    let action = if room.is_space {
+        "create-space"
+    } else {
+        "create-room"
+    };
+    let record = Record::new(&actor, action)
+        .target(&room.alias)
+        .room(&room_id);
+    config.audit.record(record).await;
+
+    if let Some(space) = &room.space {
+        let space_id = matrix::real_room_id(&config.client, space).await?;
+        matrix::add_to_space(&config.client, &space_id, &room_id, room.order.as_deref()).await?;
+
+        let record = Record::new(actor, "space-add").target(&room_id).room(space);
+        config.audit.record(record).await;
+    }
+
+    let body = json!({ "status": "ok", "room_id": room_id });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Add a room to a space or remove it.
+#[derive(Deserialize, Debug)]
+struct ApiSpace {
+    /// The API key
+    api_key: ApiKey,
+    /// The space ID or alias.
+    space: String,
+    /// The room ID or alias.
+    room_id: String,
+    /// The optional position of the room within the space.
+    order: Option<String>,
+}
+
+/// POST /space/add and POST /space/remove
+///
+/// Add a room to a space or remove it from the space.
+async fn space(
+    config: &Config,
+    request: Request<hyper::Body>,
+    add: bool,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let space: ApiSpace = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&space.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received space request (add: {}): {:?}", add, space);
+
+    let space_id = matrix::real_room_id(&config.client, &space.space).await?;
+    let room_id = matrix::real_room_id(&config.client, &space.room_id).await?;
+    let action = if add {
+        matrix::add_to_space(&config.client, &space_id, &room_id, space.order.as_deref()).await?;
+        "space-add"
+    } else {
+        matrix::remove_from_space(&config.client, &space_id, &room_id).await?;
+        "space-remove"
+    };
+
+    let record = Record::new(actor, action)
+        .target(&room_id)
+        .room(&space_id)
+        .reason(space.order.as_ref());
+    config.audit.record(record).await;
 
     *response.body_mut() = Body::from(r#"{"status": "ok" }"#);
 
     Ok(response)
 }
+
+/// Apply a moderation action to a user in all rooms.
+#[derive(Deserialize, Debug)]
+struct ApiModerate {
+    /// The API key
+    api_key: ApiKey,
+    /// The full user ID to act against.
+    user_id: String,
+    /// The action to take: `ban`, `kick` or `mute`.
+    action: Action,
+    /// The optional reason for the action.
+    reason: Option<String>,
+}
+
+/// POST /moderate
+///
+/// Ban, kick or mute a user in every managed room.
+/// Responds with the outcome for each room.
+async fn moderate(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let moderate: ApiModerate = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&moderate.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received moderate request: {:?}", moderate);
+
+    let user_id = UserId::try_from(&moderate.user_id[..])?;
+    let rooms = config.managed.get();
+    let results = moderation::everywhere(
+        &config.client,
+        &config.audit,
+        &config.store,
+        &actor,
+        &config.bot_id,
+        &rooms,
+        &user_id,
+        moderate.action,
+        moderate.reason.as_deref(),
+    )
+    .await;
+
+    let body = json!({ "status": "ok", "rooms": results });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Show or change the server ACL of all rooms.
+#[derive(Deserialize, Debug)]
+struct ApiAcl {
+    /// The API key
+    api_key: ApiKey,
+    /// The servers to deny, allow or remove.
+    #[serde(flatten)]
+    change: AclChange,
+    /// Only show what would change.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// POST /acl
+///
+/// Update the server ACL in every managed room.
+/// Responds with the resulting ACL for each room.
+/// Without any changes this shows the current ACLs.
+async fn acl(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let acl: ApiAcl = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&acl.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received acl request: {:?}", acl);
+
+    let rooms = config.managed.get();
+    let results = moderation::acl_everywhere(
+        &config.client,
+        &config.audit,
+        &actor,
+        &config.bot_id,
+        &rooms,
+        &acl.change,
+        acl.dry_run || acl.change.is_empty(),
+    )
+    .await;
+
+    let body = json!({ "status": "ok", "dry_run": acl.dry_run, "rooms": results });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Lock or unlock a room.
+#[derive(Deserialize, Debug)]
+struct ApiLock {
+    /// The API key
+    api_key: ApiKey,
+    /// The room ID or alias.
+    room_id: String,
+}
+
+/// POST /lock and POST /unlock
+///
+/// Lock a room by raising the power level required to send messages,
+/// or restore the level from before it was locked.
+/// Responds whether anything changed.
+async fn lock(
+    config: &Config,
+    request: Request<hyper::Body>,
+    lock: bool,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let room: ApiLock = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&room.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received lock request (lock: {}): {:?}", lock, room);
+
+    let room_id = matrix::real_room_id(&config.client, &room.room_id).await?;
+    let changed = if lock {
+        moderation::lock(
+            &config.client,
+            &config.audit,
+            &config.store,
+            &actor,
+            &room_id,
+        )
+        .await?
+    } else {
+        moderation::unlock(
+            &config.client,
+            &config.audit,
+            &config.store,
+            &actor,
+            &room_id,
+        )
+        .await?
+    };
+
+    let body = json!({ "status": "ok", "changed": changed });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Change the power levels of a room.
+#[derive(Deserialize, Debug)]
+struct ApiPower {
+    /// The API key
+    api_key: ApiKey,
+    /// The room ID or alias.
+    room_id: String,
+    /// The levels to set or remove.
+    #[serde(flatten)]
+    change: PowerLevelChange,
+}
+
+/// POST /power
+///
+/// Change the power levels of a room.
+/// Levels not mentioned in the request are kept as they are.
+async fn power(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let power: ApiPower = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&power.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received power request: {:?}", power);
+
+    let room_id = matrix::real_room_id(&config.client, &power.room_id).await?;
+    matrix::update_power_levels(&config.client, &room_id, &power.change).await?;
+
+    let record = Record::new(actor, "power")
+        .room(&power.room_id)
+        .reason(Some(serde_json::to_string(&power.change)?));
+    config.audit.record(record).await;
+
+    *response.body_mut() = Body::from(r#"{"status": "ok" }"#);
+
+    Ok(response)
+}
+
+/// Reconcile the rooms with a conference manifest.
+#[derive(Deserialize, Debug)]
+struct ApiProvision {
+    /// The API key
+    api_key: ApiKey,
+    /// The spaces and rooms to provision.
+    manifest: Manifest,
+    /// Only report what differs from the manifest.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// POST /provision
+///
+/// Create missing rooms and update existing ones to match the manifest.
+/// Responds with the changes for each room.
+async fn provision(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let provision: ApiProvision = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&provision.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received provision request: {:?}", provision);
+
+    let outcomes = provision::provision(
+        &config.client,
+        &config.audit,
+        &actor,
+        &config.bot_id,
+        &config.admin_users,
+        &config.templates,
+        &config.store,
+        &provision.manifest,
+        provision.dry_run,
+    )
+    .await;
+
+    let body = json!({ "status": "ok", "dry_run": provision.dry_run, "rooms": outcomes });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Schedule an announcement.
+#[derive(Deserialize, Debug)]
+struct ApiSchedule {
+    /// The API key
+    api_key: ApiKey,
+    /// The room ID or alias to send the announcement to.
+    room_id: String,
+    /// The message to send.
+    message: String,
+    /// When to send it: either `at` with a UTC time in RFC 3339 format,
+    /// or `cron` with a cron-like recurrence.
+    #[serde(flatten)]
+    when: When,
+}
+
+/// POST /announcements
+///
+/// Schedule an announcement and respond with it, including its ID.
+async fn schedule(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let schedule: ApiSchedule = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&schedule.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received schedule request: {:?}", schedule);
+
+    let room_id = matrix::real_room_id(&config.client, &schedule.room_id).await?;
+    let announcement = announcements::add(
+        &config.store,
+        &config.audit,
+        &actor,
+        room_id,
+        schedule.message,
+        schedule.when,
+    )
+    .await?;
+
+    let body = json!({ "status": "ok", "announcement": announcement });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// List the scheduled announcements.
+#[derive(Deserialize, Debug)]
+struct ApiListAnnouncements {
+    /// The API key
+    api_key: ApiKey,
+}
+
+/// POST /announcements/list
+///
+/// Respond with all scheduled announcements, the next one first.
+async fn list_announcements(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let list: ApiListAnnouncements = serde_json::from_slice(&whole_body)?;
+    if config.authorize(&list.api_key).is_none() {
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return Ok(response);
+    }
+
+    let body = json!({ "status": "ok", "announcements": announcements::list(&config.store) });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+/// Cancel a scheduled announcement.
+#[derive(Deserialize, Debug)]
+struct ApiCancelAnnouncement {
+    /// The API key
+    api_key: ApiKey,
+    /// The announcement's ID.
+    id: u64,
+}
+
+/// POST /announcements/cancel
+///
+/// Cancel a scheduled announcement.
+/// Responds whether there was an announcement with that ID.
+async fn cancel_announcement(
+    config: &Config,
+    request: Request<hyper::Body>,
+) -> anyhow::Result<Response<hyper::Body>> {
+    let mut response = Response::new(Body::empty());
+
+    let whole_body = hyper::body::to_bytes(request.into_body()).await?;
+    let cancel: ApiCancelAnnouncement = serde_json::from_slice(&whole_body)?;
+    let actor = match config.authorize(&cancel.api_key) {
+        Some(actor) => actor,
+        None => {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    };
+    log::info!("Received cancel request: {:?}", cancel);
+
+    let cancelled = announcements::cancel(&config.store, &config.audit, &actor, cancel.id).await?;
+
+    let body = json!({ "status": "ok", "changed": cancelled.is_some() });
+    *response.body_mut() = Body::from(body.to_string());
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_key_is_not_logged() {
+        let body = r##"{"api_key": "s3cret", "room_id": "#room:example.com"}"##;
+        let leave: ApiLeave = serde_json::from_str(body).unwrap();
+        let logged = format!("{:?}", leave);
+        assert!(!logged.contains("s3cret"), "{}", logged);
+        assert!(logged.contains("#room:example.com"), "{}", logged);
+    }
+}
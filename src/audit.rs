@@ -0,0 +1,134 @@
+//! Audit trail of privileged actions.
+//!
+//! Every privileged action is recorded in the moderation log room and appended to the audit file,
+//! if those are configured.
+
+use crate::matrix;
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ruma::RoomId;
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+/// A record of a privileged action.
+#[derive(Serialize, Debug, Default)]
+pub struct Record {
+    /// Who did it: a Matrix user ID or `api:<token name>`.
+    pub actor: String,
+    /// What was done.
+    pub action: String,
+    /// The user or room the action was applied to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// The room the action happened in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Why it was done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Record {
+    /// Create a new record of an action.
+    pub fn new<A: Into<String>, B: Into<String>>(actor: A, action: B) -> Self {
+        Record {
+            actor: actor.into(),
+            action: action.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the user or room the action was applied to.
+    pub fn target<S: ToString>(mut self, target: S) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    /// Set the room the action happened in.
+    pub fn room<S: ToString>(mut self, room: S) -> Self {
+        self.room = Some(room.to_string());
+        self
+    }
+
+    /// Set why the action was done.
+    pub fn reason<S: ToString>(mut self, reason: Option<S>) -> Self {
+        self.reason = reason.map(|r| r.to_string());
+        self
+    }
+}
+
+/// Where to record privileged actions.
+#[derive(Clone)]
+pub struct AuditLog {
+    client: Client,
+    room: Option<RoomId>,
+    file: Option<PathBuf>,
+}
+
+/// An audit file entry.
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    #[serde(flatten)]
+    record: &'a Record,
+}
+
+impl AuditLog {
+    /// Create a new audit log posting to the given room and appending to the given file.
+    pub fn new(client: Client, room: Option<RoomId>, file: Option<PathBuf>) -> Self {
+        AuditLog { client, room, file }
+    }
+
+    /// The moderation log room, if configured.
+    pub fn room(&self) -> Option<&RoomId> {
+        self.room.as_ref()
+    }
+
+    /// Record a privileged action.
+    ///
+    /// Failures to record are logged, but never abort the action itself.
+    pub async fn record(&self, record: Record) {
+        log::info!("Audit: {:?}", record);
+
+        if let Some(file) = &self.file {
+            if let Err(e) = self.append(file, &record).await {
+                log::error!("Failed to write to the audit file. Error: {:?}", e);
+            }
+        }
+
+        if let Some(room) = &self.room {
+            let mut msg = format!("{} {}", record.actor, record.action);
+            if let Some(target) = &record.target {
+                msg.push_str(&format!(" {}", target));
+            }
+            if let Some(in_room) = &record.room {
+                msg.push_str(&format!(" in {}", in_room));
+            }
+            if let Some(reason) = &record.reason {
+                msg.push_str(&format!(": {}", reason));
+            }
+            if let Err(e) = matrix::send_notice(&self.client, room, msg).await {
+                log::error!("Failed to post to the moderation log room. Error: {:?}", e);
+            }
+        }
+    }
+
+    async fn append(&self, file: &PathBuf, record: &Record) -> anyhow::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut line = serde_json::to_string(&Entry { timestamp, record })?;
+        line.push('\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+}
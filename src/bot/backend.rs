@@ -1,6 +1,4 @@
 use crate::strapi;
-use std::collections::HashMap;
-
 use ruma::{
     events::{
         room::message::{MessageEventContent, MessageType, TextMessageEventContent},
@@ -11,7 +9,7 @@ use ruma::{
 use serde::Serialize;
 use serde_json::{json, Value as JsonValue};
 
-use super::RoomInfo;
+use super::{polls, qa::Question, RoomInfo};
 
 #[derive(Serialize)]
 struct Data<'a, T> {
@@ -79,11 +77,11 @@ pub async fn post(
 }
 
 /// Act on room changes
-pub async fn rooms(
+pub async fn rooms<'a>(
     client: &strapi::Client,
-    all_rooms: &HashMap<RoomId, RoomInfo>,
+    all_rooms: impl Iterator<Item = (&'a RoomId, &'a RoomInfo)>,
 ) -> anyhow::Result<()> {
-    let rooms = all_rooms.values().cloned().collect::<Vec<_>>();
+    let rooms = all_rooms.map(|(_, info)| info.clone()).collect::<Vec<_>>();
 
     let client = client.clone();
     tokio::spawn(async move {
@@ -101,3 +99,59 @@ pub async fn rooms(
 
     Ok(())
 }
+
+#[derive(Serialize)]
+struct Questions {
+    channel_id: String,
+    open: bool,
+    questions: Vec<Question>,
+}
+
+/// Post the questions of a room's Q&A to the backend, most votes first.
+pub async fn questions(
+    client: &strapi::Client,
+    room_id: &RoomId,
+    open: bool,
+    questions: Vec<Question>,
+) -> anyhow::Result<()> {
+    let questions = Questions {
+        channel_id: room_id.as_str().into(),
+        open,
+        questions,
+    };
+
+    let client = client.clone();
+    tokio::spawn(async move {
+        let data = Data {
+            typ: "chat",
+            event: "qa-questions",
+            data: questions,
+        };
+        log::debug!(
+            "Sending data: {}",
+            serde_json::to_string_pretty(&data).unwrap()
+        );
+        let _ = strapi::post(&client, &client.integrations, &data).await;
+    });
+
+    Ok(())
+}
+
+/// Post the current results of a poll to the backend.
+pub async fn poll(client: &strapi::Client, results: polls::Results) -> anyhow::Result<()> {
+    let client = client.clone();
+    tokio::spawn(async move {
+        let data = Data {
+            typ: "chat",
+            event: "poll-results",
+            data: results,
+        };
+        log::debug!(
+            "Sending data: {}",
+            serde_json::to_string_pretty(&data).unwrap()
+        );
+        let _ = strapi::post(&client, &client.integrations, &data).await;
+    });
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+//! Direct messages
+//!
+//! Direct message rooms are known from the bot's `m.direct` account data
+//! and from invites marked as direct.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    api::client::r0::sync::sync_events::InvitedRoom,
+    events::{AnyGlobalAccountDataEvent, AnyStrippedStateEvent},
+    serde::Raw,
+    RoomId, UserId,
+};
+
+/// The user who invited the bot to a direct message room.
+///
+/// Returns `None` if the invite is not marked as direct.
+pub fn inviter(invite: &InvitedRoom, bot_id: &UserId) -> Option<UserId> {
+    invite
+        .invite_state
+        .events
+        .iter()
+        .flat_map(|event| event.deserialize())
+        .find_map(|event| match event {
+            AnyStrippedStateEvent::RoomMember(member)
+                if member.state_key == bot_id.as_str()
+                    && member.content.is_direct == Some(true) =>
+            {
+                Some(member.sender)
+            }
+            _ => None,
+        })
+}
+
+/// The direct message rooms listed in the `m.direct` account data, if it changed.
+pub fn from_account_data(
+    events: &[Raw<AnyGlobalAccountDataEvent>],
+) -> Option<BTreeMap<UserId, Vec<RoomId>>> {
+    events
+        .iter()
+        .rev()
+        .flat_map(|event| event.deserialize())
+        .filter_map(|event| match event {
+            AnyGlobalAccountDataEvent::Direct(direct) => Some(direct.content.0),
+            _ => None,
+        })
+        .next()
+}
@@ -0,0 +1,104 @@
+//! Word and link filter
+//!
+//! Checks messages against blocked words, patterns and link domains.
+
+use crate::config::{FilterAction, FilterConfig};
+use std::time::Duration;
+
+use regex::{Regex, RegexBuilder, RegexSet};
+
+/// A compiled message filter.
+pub struct Filter {
+    /// Matches any blocked word.
+    words: Option<Regex>,
+    /// Matches any blocked pattern.
+    patterns: RegexSet,
+    /// Finds the domains of links with a scheme or starting with `www.`.
+    links: Regex,
+    /// Finds anything that looks like a domain name, as clients turn those into links as well.
+    domains: Regex,
+    deny_domains: Vec<String>,
+    allow_domains: Vec<String>,
+    /// What to do with filtered messages.
+    pub actions: Vec<FilterAction>,
+    /// How long to mute users sending filtered messages.
+    pub mute: Duration,
+}
+
+impl Filter {
+    /// Compile the filter from the configuration.
+    pub fn new(config: &FilterConfig) -> anyhow::Result<Self> {
+        let words = if config.words.is_empty() {
+            None
+        } else {
+            let words = config
+                .words
+                .iter()
+                .map(|w| regex::escape(w))
+                .collect::<Vec<_>>()
+                .join("|");
+            let words = RegexBuilder::new(&format!(r"\b(?:{})\b", words))
+                .case_insensitive(true)
+                .build()?;
+            Some(words)
+        };
+
+        Ok(Filter {
+            words,
+            patterns: RegexSet::new(&config.patterns)?,
+            // Skips any user info, as in `https://trusted.example@scam.example`.
+            links: Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.-]*://(?:[^\s/@]*@)?|www\.)([a-z0-9.-]+)")?,
+            domains: Regex::new(r"(?i)\b((?:[a-z0-9-]+\.)+[a-z]{2,})\b")?,
+            deny_domains: lowercase(&config.deny_domains),
+            allow_domains: lowercase(&config.allow_domains),
+            actions: config.actions.clone(),
+            mute: Duration::from_secs(config.mute),
+        })
+    }
+
+    /// Check a message against the filter.
+    ///
+    /// Returns the reason if the message is filtered.
+    pub fn check(&self, body: &str) -> Option<String> {
+        if let Some(words) = &self.words {
+            if words.is_match(body) {
+                return Some("blocked word".into());
+            }
+        }
+
+        if self.patterns.is_match(body) {
+            return Some("blocked pattern".into());
+        }
+
+        for link in self.links.captures_iter(body) {
+            let domain = link[1].trim_end_matches('.').to_lowercase();
+            if matches_domain(&self.deny_domains, &domain) {
+                return Some(format!("blocked domain {}", domain));
+            }
+            if !self.allow_domains.is_empty() && !matches_domain(&self.allow_domains, &domain) {
+                return Some(format!("domain not allowed {}", domain));
+            }
+        }
+
+        // Bare domains are only checked against the deny list,
+        // file names like `main.rs` would trip the allow list otherwise.
+        for domain in self.domains.captures_iter(body) {
+            let domain = domain[1].to_lowercase();
+            if matches_domain(&self.deny_domains, &domain) {
+                return Some(format!("blocked domain {}", domain));
+            }
+        }
+
+        None
+    }
+}
+
+fn lowercase(domains: &[String]) -> Vec<String> {
+    domains.iter().map(|d| d.to_lowercase()).collect()
+}
+
+/// Check whether the domain or one of its parent domains is in the list.
+fn matches_domain(list: &[String], domain: &str) -> bool {
+    list.iter()
+        .any(|d| domain == d || domain.ends_with(&format!(".{}", d)))
+}
@@ -0,0 +1,127 @@
+//! Flood and spam detection
+//!
+//! Tracks the message rate and repeated messages of each user in each room.
+
+use crate::config::FloodConfig;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use governor::{clock::DefaultClock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
+use ruma::{RoomId, UserId};
+
+type Key = (RoomId, UserId);
+
+/// How long to remember the last message of a user who stopped sending messages.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The last message of a user in a room.
+struct LastMessage {
+    body: String,
+    /// How often it was sent in a row.
+    count: u32,
+    /// When it was last sent.
+    at: Instant,
+}
+
+/// Flood detection state across all rooms.
+pub struct Flood {
+    /// Limits the message rate per user per room.
+    limiter: RateLimiter<Key, DefaultKeyedStateStore<Key>, DefaultClock>,
+    /// The last message of each user.
+    last_messages: HashMap<Key, LastMessage>,
+    /// Users that are currently muted and when their mute ends.
+    muted_until: HashMap<Key, Instant>,
+    /// The number of identical messages in a row that are considered spam.
+    repeats: u32,
+    /// How long a flooding user is muted.
+    mute: Duration,
+    /// When users who stopped sending messages were last forgotten.
+    pruned_at: Instant,
+}
+
+impl Flood {
+    /// Create a new flood detector from the configuration.
+    pub fn new(config: &FloodConfig) -> anyhow::Result<Self> {
+        let messages = NonZeroU32::new(config.messages)
+            .ok_or_else(|| anyhow!("flood.messages must be greater than 0"))?;
+        let quota = Quota::with_period(Duration::from_secs(config.window) / messages.get())
+            .ok_or_else(|| anyhow!("flood.window must be greater than 0"))?
+            .allow_burst(messages);
+
+        Ok(Flood {
+            limiter: RateLimiter::keyed(quota),
+            last_messages: HashMap::new(),
+            muted_until: HashMap::new(),
+            repeats: config.repeats,
+            mute: Duration::from_secs(config.mute),
+            pruned_at: Instant::now(),
+        })
+    }
+
+    /// How long a flooding user is muted.
+    pub fn mute_duration(&self) -> Duration {
+        self.mute
+    }
+
+    /// Record a message sent by a user.
+    ///
+    /// Returns the reason if the user should be muted.
+    /// Users that were muted recently are not reported again until their mute ended.
+    pub fn check(&mut self, room_id: &RoomId, sender: &UserId, body: &str) -> Option<&'static str> {
+        let key = (room_id.clone(), sender.clone());
+        let now = Instant::now();
+        if now.duration_since(self.pruned_at) >= FORGET_AFTER {
+            self.prune(now);
+        }
+
+        if let Some(until) = self.muted_until.get(&key) {
+            if *until > now {
+                return None;
+            }
+            self.muted_until.remove(&key);
+        }
+
+        let repeated = {
+            let last = self
+                .last_messages
+                .entry(key.clone())
+                .or_insert_with(|| LastMessage {
+                    body: String::new(),
+                    count: 0,
+                    at: now,
+                });
+            if last.body == body {
+                last.count += 1;
+            } else {
+                last.body = body.to_string();
+                last.count = 1;
+            }
+            last.at = now;
+            self.repeats > 0 && last.count >= self.repeats
+        };
+
+        let reason = if repeated {
+            "repeated messages"
+        } else if self.limiter.check_key(&key).is_err() {
+            "flooding"
+        } else {
+            return None;
+        };
+
+        self.last_messages.remove(&key);
+        self.muted_until.insert(key, now + self.mute);
+        Some(reason)
+    }
+
+    /// Forget users who stopped sending messages and mutes that ended.
+    fn prune(&mut self, now: Instant) {
+        self.last_messages
+            .retain(|_, last| now.duration_since(last.at) < FORGET_AFTER);
+        self.muted_until.retain(|_, until| *until > now);
+        self.limiter.retain_recent();
+        self.limiter.shrink_to_fit();
+        self.pruned_at = now;
+    }
+}
@@ -2,17 +2,32 @@
 //!
 //! Messages might contain commands to run.
 
-use crate::matrix;
+use crate::{
+    announcements::{self, Recurrence, When},
+    audit::{AuditLog, Record},
+    config,
+    invites::{Invitee, Status},
+    matrix::{self, PowerLevelChange},
+    moderation::{self, AclChange, Action},
+    schedule, upgrade,
+};
 use std::convert::TryFrom;
 
-use ruma::{RoomId, UserId};
-use ruma_client;
+use ruma::{
+    events::{
+        room::message::{MessageEventContent, MessageType, Relation, TextMessageEventContent},
+        SyncMessageEvent,
+    },
+    EventId, Int, RoomId, UserId,
+};
 type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
 
+use super::{polls, tickets::Redeemed, State};
+
 enum Command {
     /// Ping-pong with the bot
     Ping,
-    /// Invite a user to the current room
+    /// Invite a user or an email address to the current room
     Invite(Vec<String>),
     /// Ask for the current list of admins
     OpAsk,
@@ -20,18 +35,82 @@ enum Command {
     Op(Vec<String>),
     /// Create a new room
     Create(Vec<String>),
+    /// Create a new space
+    CreateSpace(Vec<String>),
+    /// Add a room to a space
+    SpaceAdd(Vec<String>),
+    /// Remove a room from a space
+    SpaceRemove(Vec<String>),
+    /// Ban, kick or mute a user in all rooms
+    Everywhere(Action, Vec<String>),
+    /// Report an event or a user to the moderators
+    Report(Vec<String>),
+    /// Show or change the server ACL of all rooms
+    Acl(Vec<String>),
+    /// Set the minimum interval between messages of a user in the current room
+    Slowmode(String),
+    /// Make the current room read-only for everyone below moderator level
+    Lock,
+    /// Restore the permissions of the current room from before it was locked
+    Unlock,
+    /// Set or remove the power level of a user in the current room
+    Power(Vec<String>),
+    /// Add, list or cancel scheduled announcements
+    Schedule(Vec<String>),
+    /// Open or close Q&A in the current room, or list the top questions
+    Qa(Vec<String>),
+    /// Ask a question in the current room's Q&A
+    Ask,
+    /// Post a poll to the current room
+    Poll,
+    /// List the commands the user can run
+    Help,
+    /// Show the current and next sessions
+    Sessions,
+    /// List the rooms attendees can join
+    Rooms,
+    /// Get invited to a room
+    Join(String),
+    /// Leave the current or the given room
+    Leave(Option<String>),
+    /// Upgrade the current room to a new room version
+    Upgrade(Option<String>),
 }
 
 impl TryFrom<(&'_ str, Vec<String>)> for Command {
     type Error = anyhow::Error;
 
     fn try_from((cmd, args): (&str, Vec<String>)) -> Result<Self, Self::Error> {
-        let cmd = match (&*cmd, args.len()) {
+        let cmd = match (cmd, args.len()) {
             ("!ping", 0) => Command::Ping,
             ("!invite", 1) => Command::Invite(args),
             ("?op", 0) => Command::OpAsk,
             ("!op", _) => Command::Op(args),
             ("!create", _) => Command::Create(args),
+            ("!create-space", _) => Command::CreateSpace(args),
+            ("!space-add", 2) | ("!space-add", 3) => Command::SpaceAdd(args),
+            ("!space-remove", 2) => Command::SpaceRemove(args),
+            ("!ban-everywhere", n) if n > 0 => Command::Everywhere(Action::Ban, args),
+            ("!kick-everywhere", n) if n > 0 => Command::Everywhere(Action::Kick, args),
+            ("!mute-everywhere", n) if n > 0 => Command::Everywhere(Action::Mute, args),
+            ("!report", _) => Command::Report(args),
+            ("!acl", _) => Command::Acl(args),
+            ("!slowmode", 1) => Command::Slowmode(args.into_iter().next().unwrap()),
+            ("!lock", 0) => Command::Lock,
+            ("!unlock", 0) => Command::Unlock,
+            ("!power", 2) => Command::Power(args),
+            ("!schedule", n) if n > 0 => Command::Schedule(args),
+            ("!qa", 1) | ("!qa", 2) => Command::Qa(args),
+            ("!ask", n) if n > 0 => Command::Ask,
+            ("!poll", n) if n > 0 => Command::Poll,
+            ("!help", 0) => Command::Help,
+            ("!sessions", 0) => Command::Sessions,
+            ("!rooms", 0) => Command::Rooms,
+            ("!join", 1) => Command::Join(args.into_iter().next().unwrap()),
+            ("!leave", 0) => Command::Leave(None),
+            ("!leave", 1) => Command::Leave(args.into_iter().next()),
+            ("!upgrade", 0) => Command::Upgrade(None),
+            ("!upgrade", 1) => Command::Upgrade(args.into_iter().next()),
             _ => anyhow::bail!("invalid command"),
         };
 
@@ -39,22 +118,61 @@ impl TryFrom<(&'_ str, Vec<String>)> for Command {
     }
 }
 
+/// Commands every user can run.
+const PUBLIC_COMMANDS: &[&str] = &["!report", "!ask"];
+
+/// Commands whose messages are never relayed to the backend, so whoever ran them stays discreet.
+const PRIVATE_COMMANDS: &[&str] = &["!report"];
+
+/// Commands every user can run in direct messages with the bot.
+const DIRECT_COMMANDS: &[&str] = &["!help", "!sessions", "!rooms", "!join"];
+
+/// The reply to direct messages the bot does not understand.
+const DIRECT_HINT: &str =
+    "Sorry, I don't know what to do with that. Send !help to see what I can do.";
+
+/// The text of a message that might contain a command, without any quoted reply.
+fn command_text(event: &SyncMessageEvent<MessageEventContent>) -> Option<&str> {
+    match &event.content.msgtype {
+        MessageType::Text(TextMessageEventContent { body, .. }) => Some(strip_reply_fallback(body)),
+        _ => None,
+    }
+}
+
+/// Check whether a message runs a command that must not be relayed to the backend.
+pub fn is_private(event: &SyncMessageEvent<MessageEventContent>) -> bool {
+    command_text(event)
+        .and_then(|msg| msg.split(' ').next())
+        .is_some_and(|cmd| PRIVATE_COMMANDS.contains(&cmd))
+}
+
 /// Act on room messages
 pub async fn handle(
-    bot_id: &UserId,
-    client: &Client,
+    bot_state: &mut State,
     room_id: &RoomId,
-    sender: &UserId,
-    msg: &str,
-    admin_users: &mut Vec<String>,
+    event: &SyncMessageEvent<MessageEventContent>,
 ) -> anyhow::Result<()> {
+    let msg = match command_text(event) {
+        Some(msg) => msg,
+        None => return Ok(()),
+    };
+    let sender = &event.sender;
     log::trace!("({}) <{}> {}", room_id.as_str(), sender.localpart(), msg);
 
-    // Only admin users can run commands currently.
-    if !admin_users.contains(&sender.as_str().to_string()) {
+    // Never talk to ourselves.
+    if *sender == bot_state.bot_id {
         return Ok(());
     }
 
+    // While Q&A is open, anyone can ask questions starting with `Q:`.
+    if let Some(question) = msg
+        .get(..2)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("q:"))
+    {
+        let question = msg[question.len()..].trim();
+        return ask(bot_state, room_id, event, question, false).await;
+    }
+
     let mut parts = msg.split(' ');
     let cmd = match parts.next() {
         Some(cmd) => cmd,
@@ -62,29 +180,104 @@ pub async fn handle(
     };
     let args = parts.map(str::to_owned).collect::<Vec<_>>();
 
-    let cmd = Command::try_from((cmd, args))?;
+    // Only admin users can run commands other than the public ones,
+    // and the direct message ones in direct messages.
+    let is_admin = bot_state.admin_users.iter().any(|u| u == sender.as_str());
+    let is_direct = bot_state.is_direct(room_id);
+    let allowed =
+        is_admin || PUBLIC_COMMANDS.contains(&cmd) || (is_direct && DIRECT_COMMANDS.contains(&cmd));
+    if !allowed {
+        if is_direct {
+            matrix::send_message(&bot_state.client, room_id, DIRECT_HINT).await?;
+        }
+        return Ok(());
+    }
+
+    let cmd = match Command::try_from((cmd, args)) {
+        Ok(cmd) => cmd,
+        Err(_) if is_direct => {
+            matrix::send_message(&bot_state.client, room_id, DIRECT_HINT).await?;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let client = &bot_state.client;
+    let bot_id = &bot_state.bot_id;
+    let admin_users = &mut bot_state.admin_users;
+    let audit = &bot_state.audit;
 
     match cmd {
         Command::Ping => ping(client, room_id).await?,
-        Command::Invite(args) => invite(client, room_id, &args).await?,
+        Command::Invite(args) => invite(bot_state, room_id, sender, &args).await?,
         Command::OpAsk => op_ask(client, room_id, admin_users).await?,
-        Command::Op(args) => op(client, room_id, bot_id, admin_users, &args).await?,
-        Command::Create(args) => create(client, room_id, admin_users, &args).await?,
+        Command::Op(args) => op(client, room_id, sender, audit, bot_id, admin_users, &args).await?,
+        Command::Create(args) => create(bot_state, room_id, sender, &args, false).await?,
+        Command::CreateSpace(args) => create(bot_state, room_id, sender, &args, true).await?,
+        Command::SpaceAdd(args) => space(bot_state, room_id, sender, &args, true).await?,
+        Command::SpaceRemove(args) => space(bot_state, room_id, sender, &args, false).await?,
+        Command::Everywhere(action, args) => {
+            everywhere(bot_state, room_id, sender, action, &args).await?
+        }
+        Command::Report(args) => report(bot_state, room_id, event, &args).await?,
+        Command::Acl(args) => acl(bot_state, room_id, sender, &args).await?,
+        Command::Slowmode(arg) => slowmode(bot_state, room_id, sender, &arg).await?,
+        Command::Lock => lock(bot_state, room_id, sender, true).await?,
+        Command::Unlock => lock(bot_state, room_id, sender, false).await?,
+        Command::Power(args) => power(client, room_id, sender, audit, &args).await?,
+        Command::Schedule(args) => schedule(bot_state, room_id, sender, args).await?,
+        Command::Qa(args) => qa(bot_state, room_id, sender, &args).await?,
+        Command::Ask => {
+            let question = msg["!ask".len()..].trim();
+            ask(bot_state, room_id, event, question, true).await?
+        }
+        Command::Poll => poll(bot_state, room_id, &msg["!poll".len()..]).await?,
+        Command::Help => help(bot_state, room_id, is_admin, is_direct).await?,
+        Command::Sessions => sessions(bot_state, room_id).await?,
+        Command::Rooms => rooms(bot_state, room_id).await?,
+        Command::Join(room) => join(bot_state, room_id, sender, &room).await?,
+        Command::Leave(room) => leave(bot_state, room_id, sender, room.as_deref()).await?,
+        Command::Upgrade(version) => {
+            upgrade_room(bot_state, room_id, sender, version.as_deref()).await?
+        }
     }
 
     Ok(())
 }
 
 async fn ping(client: &Client, room_id: &RoomId) -> anyhow::Result<()> {
-    matrix::send_message(&client, &room_id, "PONG!").await?;
+    matrix::send_message(client, room_id, "PONG!").await?;
     Ok(())
 }
 
-async fn invite(client: &Client, room_id: &RoomId, args: &[String]) -> anyhow::Result<()> {
+async fn invite(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    args: &[String],
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
     let name = &args[0];
     println!("Inviting {} to {}", name, room_id);
     if !name.is_empty() {
-        matrix::invite_user(client, &room_id, name).await?
+        let invitee = Invitee::parse(name)?;
+        let status = bot_state
+            .invites
+            .invite_any(client, room_id, &invitee)
+            .await?;
+
+        let msg = match status {
+            Status::Invited => {
+                let record = Record::new(sender.as_str(), "invite")
+                    .target(name)
+                    .room(room_id);
+                bot_state.audit.record(record).await;
+                format!("Invited {}.", name)
+            }
+            Status::Banned => format!("{} is banned from this room. Unban them first.", name),
+            status => format!("{} is {}.", name, status.describe()),
+        };
+        matrix::send_message(client, room_id, msg).await?;
     }
 
     Ok(())
@@ -93,28 +286,32 @@ async fn invite(client: &Client, room_id: &RoomId, args: &[String]) -> anyhow::R
 async fn op_ask(client: &Client, room_id: &RoomId, admin_users: &[String]) -> anyhow::Result<()> {
     let users = admin_users.join(", ");
     let msg = format!("Current admins: {}", users);
-    matrix::send_message(&client, &room_id, msg).await?;
+    matrix::send_message(client, room_id, msg).await?;
     Ok(())
 }
 
 async fn op(
     client: &Client,
     room_id: &RoomId,
+    sender: &UserId,
+    audit: &AuditLog,
     bot_id: &UserId,
     admin_users: &mut Vec<String>,
     args: &[String],
 ) -> anyhow::Result<()> {
     if args.len() > 1 {
         let msg = "Invalid. Require no or one argument.";
-        matrix::send_message(&client, &room_id, msg).await?;
+        matrix::send_message(client, room_id, msg).await?;
         return Ok(());
     }
 
     if !args.is_empty() {
         let user = args[0].to_string();
         let msg = format!("Added {}", user);
+        let record = Record::new(sender.as_str(), "add-admin").target(&user);
         admin_users.push(user);
-        matrix::send_message(&client, &room_id, msg).await?;
+        matrix::send_message(client, room_id, msg).await?;
+        audit.record(record).await;
     }
 
     let mut users = admin_users
@@ -123,37 +320,1055 @@ async fn op(
         .collect::<Vec<_>>();
     users.push(bot_id.clone());
 
-    let _ = matrix::op_user(&client, room_id, &users).await;
+    if matrix::op_user(client, room_id, &users).await.is_ok() {
+        let record = Record::new(sender.as_str(), "op").room(room_id);
+        audit.record(record).await;
+    }
     Ok(())
 }
 
 async fn create(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    args: &[String],
+    space: bool,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+
+    let mut args = args.to_vec();
+    let parent = take_option(&mut args, "--space");
+    if args.len() != 2 && args.len() != 3 {
+        let msg = "Need arguments: <room alias> <room name> [template] [--space <space>]";
+        matrix::send_message(client, room_id, msg).await?;
+        return Ok(());
+    }
+
+    let alias = &args[0];
+    let name = &args[1];
+    let template = match config::find_template(&bot_state.templates, args.get(2).map(|t| &t[..])) {
+        Ok(template) => template,
+        Err(e) => {
+            matrix::send_message(client, room_id, e.to_string()).await?;
+            return Ok(());
+        }
+    };
+    let parent = match parent {
+        Some(parent) => Some(matrix::real_room_id(client, &parent).await?),
+        None => None,
+    };
+    let invites = bot_state
+        .admin_users
+        .iter()
+        .map(|u| UserId::try_from(&u[..]).unwrap())
+        .collect::<Vec<_>>();
+    let msg = format!(
+        "Will create a {} named #{}:{} with the name: {}. You will be invited.",
+        if space { "space" } else { "room" },
+        alias,
+        bot_state.bot_id.server_name(),
+        name
+    );
+    matrix::send_message(client, room_id, msg).await?;
+    let new_room_id =
+        matrix::create_room(client, alias, name, None, &invites, &template, space).await?;
+    let template_name = args.get(2).map_or(config::DEFAULT_TEMPLATE, |t| &t[..]);
+    bot_state.store.update(|data| {
+        data.room_templates
+            .insert(new_room_id.clone(), template_name.to_string())
+    })?;
+
+    let action = if space { "create-space" } else { "create-room" };
+    let record = Record::new(sender.as_str(), action)
+        .target(alias)
+        .room(&new_room_id);
+    bot_state.audit.record(record).await;
+
+    if let Some(parent) = parent {
+        matrix::add_to_space(client, &parent, &new_room_id, None).await?;
+        let record = Record::new(sender.as_str(), "space-add")
+            .target(&new_room_id)
+            .room(&parent);
+        bot_state.audit.record(record).await;
+    }
+
+    Ok(())
+}
+
+/// Take the value of an option like `--space <space>` out of the arguments.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|a| a == name)?;
+    args.remove(pos);
+    if pos < args.len() {
+        Some(args.remove(pos))
+    } else {
+        None
+    }
+}
+
+async fn space(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    args: &[String],
+    add: bool,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+
+    let space_id = matrix::real_room_id(client, &args[0]).await?;
+    let child_id = matrix::real_room_id(client, &args[1]).await?;
+    let order = args.get(2).map(|o| &o[..]);
+    let (action, msg) = if add {
+        matrix::add_to_space(client, &space_id, &child_id, order).await?;
+        ("space-add", format!("Added {} to {}.", args[1], args[0]))
+    } else {
+        matrix::remove_from_space(client, &space_id, &child_id).await?;
+        (
+            "space-remove",
+            format!("Removed {} from {}.", args[1], args[0]),
+        )
+    };
+
+    let record = Record::new(sender.as_str(), action)
+        .target(&child_id)
+        .room(&space_id)
+        .reason(order);
+    bot_state.audit.record(record).await;
+
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn everywhere(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    action: Action,
+    args: &[String],
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let all_room_info = &bot_state.all_room_info;
+
+    let user_id = match UserId::try_from(&args[0][..]) {
+        Ok(user_id) => user_id,
+        Err(_) => {
+            let msg = format!("Invalid user ID: {}", args[0]);
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+    let reason = args[1..].join(" ");
+    let reason = if reason.is_empty() {
+        None
+    } else {
+        Some(&reason[..])
+    };
+
+    let rooms = bot_state.managed_rooms();
+    let results = moderation::everywhere(
+        client,
+        &bot_state.audit,
+        &bot_state.store,
+        sender.as_str(),
+        &bot_state.bot_id,
+        &rooms,
+        &user_id,
+        action,
+        reason,
+    )
+    .await;
+
+    let succeeded = results.iter().filter(|r| r.error.is_none()).count();
+    let mut msg = format!(
+        "{} {} in {} of {} rooms.",
+        action.past_tense(),
+        user_id,
+        succeeded,
+        results.len()
+    );
+    for result in results {
+        let room_name = all_room_info
+            .get(&result.room_id)
+            .map(|info| info.display_name())
+            .unwrap_or_else(|| result.room_id.as_str());
+        match &result.error {
+            None => msg.push_str(&format!("\n- {}: ok", room_name)),
+            Some(e) => msg.push_str(&format!("\n- {}: failed ({})", room_name, e)),
+        }
+    }
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+/// Strip the quoted original message from a reply.
+///
+/// Replies start with the quoted message (lines starting with `>`), followed by an empty line.
+fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    match body.find("\n\n") {
+        Some(idx) => &body[idx + 2..],
+        None => body,
+    }
+}
+
+/// What a report is about.
+enum ReportTarget {
+    /// A single event in a room.
+    Event(RoomId, EventId),
+    /// A user.
+    User(UserId),
+}
+
+async fn report(
+    bot_state: &State,
+    room_id: &RoomId,
+    event: &SyncMessageEvent<MessageEventContent>,
+    args: &[String],
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let reporter = &event.sender;
+
+    // Remove the report command so the reporter stays discreet.
+    if let Err(e) = matrix::redact_event(client, room_id, &event.event_id, None).await {
+        log::error!("Failed to redact report command. Error: {:?}", e);
+    }
+
+    let reply_to = match &event.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.clone()),
+        _ => None,
+    };
+
+    let (target, reason) = match (reply_to, args.first()) {
+        (Some(event_id), _) => (
+            Some(ReportTarget::Event(room_id.clone(), event_id)),
+            args.join(" "),
+        ),
+        (None, Some(target)) => {
+            let target = if let Some((room, event_id)) = matrix::parse_event_link(target) {
+                // An unknown room in the link gets the usage reply, like any other bad target.
+                match matrix::real_room_id(client, &room).await {
+                    Ok(room_id) => Some(ReportTarget::Event(room_id, event_id)),
+                    Err(e) => {
+                        log::warn!("Failed to resolve reported room {}. Error: {:?}", room, e);
+                        None
+                    }
+                }
+            } else if let Ok(user_id) = UserId::try_from(&target[..]) {
+                Some(ReportTarget::User(user_id))
+            } else {
+                None
+            };
+            (target, args[1..].join(" "))
+        }
+        (None, None) => (None, String::new()),
+    };
+
+    let target = match target {
+        Some(target) => target,
+        None => {
+            let msg = format!(
+                "{}: Usage: !report <event link or user> <reason>, or reply to a message with !report <reason>",
+                reporter
+            );
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let room_name = bot_state
+        .all_room_info
+        .get(room_id)
+        .map(|info| info.display_name())
+        .unwrap_or_else(|| room_id.as_str());
+    let about = match &target {
+        ReportTarget::Event(event_room_id, event_id) => {
+            // Also let the homeserver administrators know.
+            if let Err(e) = matrix::report_content(client, event_room_id, event_id, &reason).await {
+                log::error!("Failed to report event to the homeserver. Error: {:?}", e);
+            }
+            matrix::event_link(event_room_id, event_id)
+        }
+        ReportTarget::User(user_id) => user_id.to_string(),
+    };
+    let msg = format!(
+        "Report by {} in {} about {}: {}",
+        reporter, room_name, about, reason
+    );
+
+    match &bot_state.mod_room {
+        Some(mod_room) => matrix::send_message(client, mod_room, msg).await?,
+        None => log::warn!("No moderation room configured. {}", msg),
+    }
+
+    if bot_state.is_direct(room_id) {
+        let msg = "Thank you, the moderators have been notified.";
+        matrix::send_message(client, room_id, msg).await?;
+    }
+
+    Ok(())
+}
+
+async fn acl(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    args: &[String],
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let args = args
+        .iter()
+        .filter(|a| *a != "--dry-run")
+        .collect::<Vec<_>>();
+
+    let mut change = AclChange::default();
+    match &args[..] {
+        [] => {}
+        [op, server] if *op == "deny" => change.deny.push(server.to_string()),
+        [op, server] if *op == "allow" => change.allow.push(server.to_string()),
+        [op, server] if *op == "remove" => change.remove.push(server.to_string()),
+        _ => {
+            let msg = "Usage: !acl [deny|allow|remove <server>] [--dry-run]";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    }
+    // Showing the current ACLs is a change that does nothing.
+    let dry_run = dry_run || change.is_empty();
+
+    let rooms = bot_state.managed_rooms();
+    let results = moderation::acl_everywhere(
+        client,
+        &bot_state.audit,
+        sender.as_str(),
+        &bot_state.bot_id,
+        &rooms,
+        &change,
+        dry_run,
+    )
+    .await;
+
+    let mut msg = if dry_run && !change.is_empty() {
+        String::from("Server ACLs (dry run, nothing changed):")
+    } else {
+        String::from("Server ACLs:")
+    };
+    for result in &results {
+        let room_name = bot_state
+            .all_room_info
+            .get(&result.room_id)
+            .map(|info| info.display_name())
+            .unwrap_or_else(|| result.room_id.as_str());
+        match &result.error {
+            None => {
+                let changed = match (result.changed, dry_run) {
+                    (true, true) => " (would change)",
+                    (true, false) => " (changed)",
+                    (false, _) => "",
+                };
+                msg.push_str(&format!(
+                    "\n- {}: allow {}, deny {}{}",
+                    room_name,
+                    result.allow.join(" "),
+                    result.deny.join(" "),
+                    changed
+                ));
+            }
+            Some(e) => msg.push_str(&format!("\n- {}: failed ({})", room_name, e)),
+        }
+    }
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn slowmode(
+    bot_state: &mut State,
+    room_id: &RoomId,
+    sender: &UserId,
+    arg: &str,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+
+    let interval = match arg {
+        "off" | "0" => None,
+        secs => match secs.parse::<u64>() {
+            Ok(secs) => Some(secs),
+            Err(_) => {
+                let msg = "Usage: !slowmode <seconds>|off";
+                matrix::send_message(client, room_id, msg).await?;
+                return Ok(());
+            }
+        },
+    };
+
+    bot_state.store.update(|data| match interval {
+        Some(secs) => data.slowmode.insert(room_id.clone(), secs),
+        None => data.slowmode.remove(room_id),
+    })?;
+    if interval.is_none() {
+        bot_state
+            .last_message_at
+            .retain(|(room, _), _| room != room_id);
+    }
+
+    let (action, msg) = match interval {
+        Some(secs) => (
+            "slowmode",
+            format!("Slow mode enabled: one message every {} seconds.", secs),
+        ),
+        None => ("slowmode off", String::from("Slow mode disabled.")),
+    };
+    let record = Record::new(sender.as_str(), action)
+        .room(room_id)
+        .reason(interval.map(|secs| format!("{} seconds", secs)));
+    bot_state.audit.record(record).await;
+
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn lock(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    lock: bool,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let audit = &bot_state.audit;
+    let store = &bot_state.store;
+
+    let msg = if lock {
+        match moderation::lock(client, audit, store, sender.as_str(), room_id).await? {
+            true => "Room locked. Only moderators can send messages now.",
+            false => "Room is already locked.",
+        }
+    } else {
+        match moderation::unlock(client, audit, store, sender.as_str(), room_id).await? {
+            true => "Room unlocked.",
+            false => "Room is not locked.",
+        }
+    };
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn power(
     client: &Client,
     room_id: &RoomId,
-    admin_users: &mut Vec<String>,
+    sender: &UserId,
+    audit: &AuditLog,
     args: &[String],
 ) -> anyhow::Result<()> {
-    if args.len() != 2 {
-        matrix::send_message(
-            &client,
-            &room_id,
-            "Need arguments: <room alias> <room name>",
+    let user_id = UserId::try_from(&args[0][..]);
+    let level = match &args[1][..] {
+        "remove" => Ok(None),
+        level => level.parse::<i32>().map(|level| Some(Int::from(level))),
+    };
+    let (user_id, level) = match (user_id, level) {
+        (Ok(user_id), Ok(level)) => (user_id, level),
+        _ => {
+            let msg = "Usage: !power <user id> <level>|remove";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let change = match level {
+        Some(level) => PowerLevelChange::set_users(std::slice::from_ref(&user_id), level),
+        None => PowerLevelChange {
+            remove_users: vec![user_id.clone()],
+            ..Default::default()
+        },
+    };
+    matrix::update_power_levels(client, room_id, &change).await?;
+
+    let reason = match level {
+        Some(level) => format!("level {}", level),
+        None => String::from("removed"),
+    };
+    let record = Record::new(sender.as_str(), "power")
+        .target(&user_id)
+        .room(room_id)
+        .reason(Some(&reason));
+    audit.record(record).await;
+
+    let msg = format!("Power level of {}: {}", user_id, reason);
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn schedule(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    mut args: Vec<String>,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let audit = &bot_state.audit;
+    let store = &bot_state.store;
+    let usage = "Usage: !schedule add <time>|every <minute> <hour> <day> <month> <weekday> [--room <room>] <message>, !schedule list, !schedule cancel <id>";
+
+    let subcommand = args.remove(0);
+    let msg = match (&subcommand[..], &args[..]) {
+        ("list", []) => {
+            let announcements = announcements::list(store);
+            if announcements.is_empty() {
+                String::from("No announcements scheduled.")
+            } else {
+                announcements
+                    .iter()
+                    .map(|announcement| {
+                        let repeat = match &announcement.repeat {
+                            Some(recurrence) => format!(" (every {})", recurrence),
+                            None => String::new(),
+                        };
+                        format!(
+                            "{}: {} UTC{} in {}: {}",
+                            announcement.id,
+                            announcement.at.format("%Y-%m-%d %H:%M"),
+                            repeat,
+                            announcement.room_id,
+                            announcement.message
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        }
+        ("cancel", [id]) => match id.parse() {
+            Ok(id) => match announcements::cancel(store, audit, sender.as_str(), id).await? {
+                Some(_) => format!("Announcement {} cancelled.", id),
+                None => format!("No announcement {}.", id),
+            },
+            Err(_) => String::from(usage),
+        },
+        ("add", _) => {
+            let target = match take_option(&mut args, "--room") {
+                Some(room) => matrix::real_room_id(client, &room).await?,
+                None => room_id.clone(),
+            };
+            let when = match args.first().map(|a| &a[..]) {
+                Some("every") if args.len() > 6 => {
+                    let expr = args.drain(..6).skip(1).collect::<Vec<_>>().join(" ");
+                    Recurrence::try_from(expr).map(When::Cron)
+                }
+                Some(time) if args.len() > 1 => {
+                    let when = When::parse_time(time);
+                    args.remove(0);
+                    when
+                }
+                _ => Err(anyhow::anyhow!("missing time or message")),
+            };
+            match when {
+                Ok(when) => {
+                    let message = args.join(" ");
+                    match announcements::add(store, audit, sender.as_str(), target, message, when)
+                        .await
+                    {
+                        Ok(announcement) => format!(
+                            "Announcement {} scheduled for {} UTC.",
+                            announcement.id,
+                            announcement.at.format("%Y-%m-%d %H:%M")
+                        ),
+                        Err(e) => format!("Can't schedule the announcement: {}.", e),
+                    }
+                }
+                Err(e) => format!("Invalid schedule: {}. {}", e, usage),
+            }
+        }
+        _ => String::from(usage),
+    };
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn qa(
+    bot_state: &mut State,
+    room_id: &RoomId,
+    sender: &UserId,
+    args: &[String],
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+
+    let msg = match (&args[0][..], args.get(1)) {
+        ("start", None) => {
+            if bot_state.qa.start(room_id) {
+                let record = Record::new(sender.as_str(), "qa start").room(room_id);
+                bot_state.audit.record(record).await;
+                bot_state.post_questions(room_id).await;
+                String::from("Q&A is open! Ask by starting your message with \"Q:\" or with !ask <question>, and vote for questions with 👍.")
+            } else {
+                String::from("Q&A is already open.")
+            }
+        }
+        ("stop", None) => match bot_state.qa.stop(room_id) {
+            Some(session) => {
+                let record = Record::new(sender.as_str(), "qa stop").room(room_id);
+                bot_state.audit.record(record).await;
+                bot_state.post_questions(room_id).await;
+                format!("Q&A is closed. {} questions asked.", session.top().len())
+            }
+            None => String::from("Q&A is not open."),
+        },
+        ("top", count) => {
+            let count = match count.map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) => count,
+                Some(Err(_)) => {
+                    let msg = "Usage: !qa start|stop|top [count]";
+                    matrix::send_message(client, room_id, msg).await?;
+                    return Ok(());
+                }
+                None => 5,
+            };
+            match bot_state.qa.get(room_id) {
+                Some(session) => {
+                    let questions = session.top();
+                    if questions.is_empty() {
+                        String::from("No questions yet.")
+                    } else {
+                        questions
+                            .iter()
+                            .take(count)
+                            .enumerate()
+                            .map(|(i, question)| {
+                                format!(
+                                    "{}. ({} 👍) {} ({})",
+                                    i + 1,
+                                    question.votes,
+                                    question.question,
+                                    question.sender
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    }
+                }
+                None => String::from("Q&A is not open."),
+            }
+        }
+        _ => String::from("Usage: !qa start|stop|top [count]"),
+    };
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+/// Collect a question if Q&A is open in the room.
+///
+/// With `reply`, the sender is told if Q&A is not open.
+async fn ask(
+    bot_state: &mut State,
+    room_id: &RoomId,
+    event: &SyncMessageEvent<MessageEventContent>,
+    question: &str,
+    reply: bool,
+) -> anyhow::Result<()> {
+    if question.is_empty() {
+        return Ok(());
+    }
+
+    if bot_state
+        .qa
+        .ask(room_id, &event.event_id, &event.sender, question)
+    {
+        bot_state.post_questions(room_id).await;
+    } else if reply {
+        let msg = "Q&A is not open in this room.";
+        matrix::send_message(&bot_state.client, room_id, msg).await?;
+    }
+
+    Ok(())
+}
+
+async fn poll(bot_state: &State, room_id: &RoomId, args: &str) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let usage = format!(
+        "Usage: !poll \"<question>\" \"<option>\" \"<option>\" ... [--for <duration, like 90s, 5m or 1h>], with 2 to {} options",
+        polls::OPTIONS.len()
+    );
+
+    let mut args = split_quoted(args);
+    let duration = match take_option(&mut args, "--for") {
+        Some(duration) => polls::parse_duration(&duration),
+        None => Some(polls::DEFAULT_DURATION),
+    };
+    let duration = match duration {
+        Some(duration) if (3..=polls::OPTIONS.len() + 1).contains(&args.len()) => duration,
+        _ => {
+            matrix::send_message(client, room_id, usage).await?;
+            return Ok(());
+        }
+    };
+
+    let question = args.remove(0);
+    bot_state
+        .polls
+        .open(
+            client,
+            &bot_state.strapi_client,
+            room_id,
+            question,
+            args,
+            duration,
         )
-        .await?;
+        .await
+}
+
+/// Split arguments on whitespace, keeping quoted arguments together.
+///
+/// Both straight and curly double quotes work, as some clients replace them while typing.
+fn split_quoted(args: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in args.chars() {
+        match c {
+            '"' | '“' | '”' => {
+                // An empty quoted argument is still an argument.
+                if quoted || !current.is_empty() {
+                    parts.push(std::mem::take(&mut current));
+                }
+                quoted = !quoted;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    parts.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+async fn help(
+    bot_state: &State,
+    room_id: &RoomId,
+    is_admin: bool,
+    is_direct: bool,
+) -> anyhow::Result<()> {
+    let mut lines = vec!["Here is what I can do:"];
+    if is_direct {
+        lines.extend(&[
+            "!sessions - Show the current and next sessions",
+            "!rooms - List the rooms you can join",
+            "!join <room or ticket code> - Get invited to a room, or to the rooms of your ticket",
+        ]);
+    }
+    lines.extend(&[
+        "!report <event link or user id> <reason> - Report a message or a user to the moderators",
+        "!ask <question> - Ask a question while Q&A is open",
+    ]);
+    if is_admin {
+        lines.push("As an admin you can run all other commands, see the bot's README.");
+    }
+    matrix::send_message(&bot_state.client, room_id, lines.join("\n")).await?;
+
+    Ok(())
+}
+
+async fn sessions(bot_state: &State, room_id: &RoomId) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let config = match &bot_state.schedule {
+        Some(config) => config,
+        None => {
+            let msg = "There is no schedule available.";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let sessions = schedule::fetch(&bot_state.strapi_client, &config.endpoint).await?;
+    let rooms = schedule::by_room(client, config, sessions).await;
+    let now = chrono::Utc::now();
+    let lines = rooms
+        .iter()
+        .filter_map(|(session_room, sessions)| {
+            let (current, next) = schedule::current_and_next(sessions, now);
+            let topic = schedule::topic(current, next)?;
+            let name = bot_state
+                .all_room_info
+                .get(session_room)
+                .map(|info| info.display_name())
+                .unwrap_or_else(|| session_room.as_str());
+            Some(format!("{}: {}", name, topic))
+        })
+        .collect::<Vec<_>>();
+
+    let msg = if lines.is_empty() {
+        String::from("No more sessions scheduled.")
     } else {
-        let alias = &args[0];
-        let name = &args[1];
-        let invites = admin_users
+        lines.join("\n")
+    };
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn rooms(bot_state: &State, room_id: &RoomId) -> anyhow::Result<()> {
+    let msg = if bot_state.open_rooms.is_empty() {
+        String::from("There are no rooms to join.")
+    } else {
+        let rooms = bot_state
+            .open_rooms
             .iter()
-            .map(|u| UserId::try_from(&u[..]).unwrap())
+            .map(|open_room| {
+                bot_state
+                    .all_room_info
+                    .get(open_room)
+                    .map(|info| info.display_name())
+                    .unwrap_or_else(|| open_room.as_str())
+            })
             .collect::<Vec<_>>();
-        let msg = format!(
-            "Will create a room named #{}:rustch.at with the name: {}. You will be invited.",
-            alias, name
-        );
-        matrix::send_message(&client, &room_id, msg).await?;
-        matrix::create_room(client, &alias, &name, None, &invites).await?;
+        format!(
+            "You can join these rooms with !join <room>:\n{}",
+            rooms.join("\n")
+        )
+    };
+    matrix::send_message(&bot_state.client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn join(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    room: &str,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+
+    // Anything that is not a room ID or alias is a ticket code.
+    if !room.starts_with('#') && !room.starts_with('!') {
+        return redeem_ticket(bot_state, room_id, sender, room).await;
+    }
+
+    let target = matrix::find_room(client, room).await.ok().flatten();
+    let target = match target.filter(|target| bot_state.open_rooms.contains(target)) {
+        Some(target) => target,
+        None => {
+            let msg = format!(
+                "You can't join {}. Send !rooms to see the rooms you can join.",
+                room
+            );
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let msg = match bot_state.invites.invite(client, &target, sender).await? {
+        Status::Invited => {
+            let record = Record::new(sender.as_str(), "self-invite")
+                .target(sender)
+                .room(&target);
+            bot_state.audit.record(record).await;
+            format!("Invited you to {}.", room)
+        }
+        Status::AlreadyInvited => format!(
+            "You already have an invite to {}. Check your pending invites.",
+            room
+        ),
+        Status::AlreadyJoined => format!("You already are in {}.", room),
+        Status::Banned | Status::Failed => format!("You can't join {}.", room),
+    };
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn redeem_ticket(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    code: &str,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let tickets = match &bot_state.tickets {
+        Some(tickets) => tickets,
+        None => {
+            let msg = "Send !rooms to see the rooms you can join.";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let redeemed = tickets
+        .redeem(&bot_state.strapi_client, &bot_state.store, sender, code)
+        .await?;
+    let (typ, rooms) = match redeemed {
+        Redeemed::Rooms(typ, rooms) => (typ, rooms),
+        Redeemed::Invalid => {
+            let msg = "That is not a valid ticket code.";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+        Redeemed::UsedUp => {
+            let msg = "This ticket code was already used. Please contact the organizers if this is your ticket.";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+        Redeemed::TooManyAttempts => {
+            let msg = "Too many attempts. Please try again in a minute.";
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let record = Record::new(sender.as_str(), "redeem-ticket")
+        .target(sender)
+        .reason(Some(format!("ticket type {}", typ)));
+    bot_state.audit.record(record).await;
+
+    let mut invited = vec![];
+    let mut member = vec![];
+    let mut failed = vec![];
+    for target in &rooms {
+        let name = bot_state
+            .all_room_info
+            .get(target)
+            .map(|info| info.display_name())
+            .unwrap_or_else(|| target.as_str());
+        match bot_state.invites.invite(client, target, sender).await {
+            Ok(Status::Invited) => invited.push(name),
+            Ok(Status::AlreadyInvited) | Ok(Status::AlreadyJoined) => member.push(name),
+            Ok(status) => {
+                log::warn!(
+                    "Not inviting {} to {}: {}",
+                    sender,
+                    target,
+                    status.describe()
+                );
+                failed.push(name);
+            }
+            Err(e) => {
+                log::error!("Failed to invite {} to {}. Error: {:?}", sender, target, e);
+                failed.push(name);
+            }
+        }
+    }
+
+    let mut msg = String::from("Your ticket is valid.");
+    if !invited.is_empty() {
+        msg.push_str(&format!(" Invited you to {}.", invited.join(", ")));
+    }
+    if !member.is_empty() {
+        msg.push_str(&format!(
+            " You are already invited to or in {}.",
+            member.join(", ")
+        ));
+    }
+    if !failed.is_empty() {
+        msg.push_str(&format!(
+            " I could not invite you to {}. Please contact the organizers.",
+            failed.join(", ")
+        ));
+    }
+    matrix::send_message(client, room_id, msg).await?;
+
+    Ok(())
+}
+
+async fn leave(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    room: Option<&str>,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let target = match room {
+        Some(room) => matrix::real_room_id(client, room).await?,
+        None => room_id.clone(),
+    };
+
+    if target == *room_id {
+        matrix::send_message(client, room_id, "Goodbye!").await?;
+    }
+    matrix::leave_room(client, &target).await?;
+    let record = Record::new(sender.as_str(), "leave").room(&target);
+    bot_state.audit.record(record).await;
+
+    if target != *room_id {
+        let msg = format!("Left {}.", room.unwrap_or_else(|| target.as_str()));
+        matrix::send_message(client, room_id, msg).await?;
+    }
+
+    Ok(())
+}
+
+async fn upgrade_room(
+    bot_state: &State,
+    room_id: &RoomId,
+    sender: &UserId,
+    version: Option<&str>,
+) -> anyhow::Result<()> {
+    let client = &bot_state.client;
+    let audit = &bot_state.audit;
+
+    let upgrade = match upgrade::upgrade(client, audit, sender.as_str(), room_id, version).await {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            let msg = format!("Failed to upgrade the room: {}", e);
+            matrix::send_message(client, room_id, msg).await?;
+            return Ok(());
+        }
+    };
+
+    let mut msg = format!(
+        "Upgraded the room to version {}. The new room is {}.",
+        upgrade.version, upgrade.room_id
+    );
+    if !upgrade.failed.is_empty() {
+        msg.push_str(&format!(
+            "\nFailed to carry over:\n{}",
+            upgrade.failed.join("\n")
+        ));
     }
+    matrix::send_message(client, room_id, msg).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_plain_arguments() {
+        assert_eq!(split_quoted("a b  c"), vec!["a", "b", "c"]);
+        assert_eq!(split_quoted("  a\tb\n"), vec!["a", "b"]);
+        assert!(split_quoted("").is_empty());
+        assert!(split_quoted("   ").is_empty());
+    }
+
+    #[test]
+    fn split_quoted_arguments() {
+        assert_eq!(
+            split_quoted(r#""What now?" "Go on"  stop --for 5m"#),
+            vec!["What now?", "Go on", "stop", "--for", "5m"]
+        );
+        assert_eq!(
+            split_quoted("“Curly quotes” “work too”"),
+            vec!["Curly quotes", "work too"]
+        );
+        assert_eq!(split_quoted(r#""" a"#), vec!["", "a"]);
+        assert_eq!(split_quoted(r#"ab"c d""#), vec!["ab", "c d"]);
+    }
+
+    #[test]
+    fn split_unterminated_quote() {
+        assert_eq!(split_quoted(r#"a "b c"#), vec!["a", "b c"]);
+    }
+}
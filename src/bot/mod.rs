@@ -4,43 +4,111 @@
 //! It waits for messages from the server, updates its internal state about rooms,
 //! reacts to invitations and commands and relays received messages.
 
-use crate::{matrix, strapi};
-use std::collections::{BTreeMap, HashMap};
+use crate::{
+    audit::{AuditLog, Record},
+    config::{
+        DirectConfig, FilterAction, ModerationConfig, RoomTemplate, ScheduleConfig, WelcomeConfig,
+        WelcomeVia,
+    },
+    invites::InviteQueue,
+    matrix,
+    moderation::{self, Action},
+    store::Store,
+    strapi,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures_util::stream::TryStreamExt as _;
 use ruma::{
     api::client::r0::{
         membership::join_room_by_id,
-        sync::sync_events::{self, InvitedRoom, JoinedRoom},
+        sync::sync_events::{self, InvitedRoom, JoinedRoom, LeftRoom},
     },
     events::{
         room::{
-            member::MembershipState,
-            message::{MessageEventContent, MessageType, TextMessageEventContent},
+            member::{MembershipChange, MembershipState},
+            message::{
+                EmoteMessageEventContent, MessageEventContent, MessageType,
+                NoticeMessageEventContent, TextMessageEventContent,
+            },
+            power_levels::PowerLevelsEventContent,
         },
         AnySyncMessageEvent, AnySyncRoomEvent, AnySyncStateEvent, SyncMessageEvent, SyncStateEvent,
     },
     presence::PresenceState,
-    RoomId, UserId,
+    EventId, Int, RoomId, UserId,
 };
-use ruma_client;
 type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
 use serde::Serialize;
 
 mod backend;
+mod direct;
+mod filter;
+mod flood;
 mod messages;
+mod policy;
+mod polls;
+mod qa;
+mod reactions;
+mod spaces;
+mod tickets;
+mod welcome;
 
 /// The bot's main event loop.
 ///
 /// Continously stream server responses and handle all state changes and messages.
+#[allow(clippy::too_many_arguments)]
 pub async fn event_loop(
     bot_id: UserId,
     client: Client,
     admin_users: Vec<String>,
     strapi_client: strapi::Client,
+    moderation: ModerationConfig,
+    audit: AuditLog,
+    store: Store,
+    templates: BTreeMap<String, RoomTemplate>,
+    welcome: WelcomeConfig,
+    direct: DirectConfig,
+    schedule: Option<ScheduleConfig>,
+    invites: InviteQueue,
+    managed: ManagedRooms,
 ) -> anyhow::Result<()> {
+    let mod_room = match moderation.room {
+        Some(room) => Some(matrix::real_room_id(&client, &room).await?),
+        None => None,
+    };
+    let flood = match moderation.flood {
+        Some(config) => Some(flood::Flood::new(&config)?),
+        None => None,
+    };
+    let filter = match moderation.filter {
+        Some(config) => Some(filter::Filter::new(&config)?),
+        None => None,
+    };
+    let welcome = welcome::Welcome::new(&client, welcome).await?;
+    let tickets = match direct.tickets {
+        Some(config) => Some(tickets::Tickets::new(&client, config).await?),
+        None => None,
+    };
+    let mut open_rooms = vec![];
+    for room in &direct.rooms {
+        open_rooms.push(matrix::real_room_id(&client, room).await?);
+    }
+    // Rooms the bot opened itself before it kept `m.direct` up to date.
+    let direct_rooms = store.read(|data| {
+        data.direct_rooms
+            .iter()
+            .map(|(user_id, room_id)| (room_id.clone(), user_id.clone()))
+            .collect()
+    });
+    let mut policy_rooms = HashSet::new();
+    for room in &moderation.policy_rooms {
+        policy_rooms.insert(matrix::join_room(&client, room).await?);
+    }
+
     let initial_sync_response = client.send_request(sync_events::Request::new()).await?;
     log::trace!("Initial Sync: {:#?}", initial_sync_response);
 
@@ -51,6 +119,23 @@ pub async fn event_loop(
         all_room_info: HashMap::new(),
         strapi_client,
         pending_invites: HashMap::new(),
+        mod_room,
+        flood,
+        filter,
+        audit,
+        policy: policy::PolicyLists::new(policy_rooms),
+        store,
+        last_message_at: HashMap::new(),
+        templates,
+        qa: qa::QaSessions::default(),
+        polls: polls::Polls::default(),
+        welcome,
+        direct_rooms,
+        open_rooms,
+        tickets,
+        schedule,
+        invites,
+        managed,
     };
 
     let next_batch = initial_sync_response.next_batch.clone();
@@ -84,6 +169,44 @@ pub struct RoomInfo {
     alias: Option<String>,
     /// The room's topic, if known.
     topic: Option<String>,
+    /// The room's power levels, if known.
+    #[serde(skip)]
+    power_levels: Option<PowerLevelsEventContent>,
+    /// Whether the room is a space.
+    is_space: bool,
+    /// The rooms in this space, in order.
+    children: Vec<spaces::SpaceChild>,
+    /// The IDs of the spaces this room is in.
+    parents: Vec<String>,
+}
+
+impl RoomInfo {
+    /// A human-readable name for the room.
+    ///
+    /// Uses the canonical alias or the name if known, the room ID otherwise.
+    fn display_name(&self) -> &str {
+        self.alias
+            .as_deref()
+            .or(self.name.as_deref())
+            .unwrap_or(&self.id)
+    }
+}
+
+/// The rooms the bot manages, shared with the API.
+///
+/// Cloning it gives another handle to the same list.
+#[derive(Clone, Default)]
+pub struct ManagedRooms(Arc<Mutex<Vec<RoomId>>>);
+
+impl ManagedRooms {
+    /// The managed rooms, as of the last sync.
+    pub fn get(&self) -> Vec<RoomId> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, rooms: Vec<RoomId>) {
+        *self.0.lock().unwrap() = rooms;
+    }
 }
 
 struct State {
@@ -93,6 +216,31 @@ struct State {
     all_room_info: HashMap<RoomId, RoomInfo>,
     strapi_client: strapi::Client,
     pending_invites: HashMap<RoomId, usize>,
+    mod_room: Option<RoomId>,
+    flood: Option<flood::Flood>,
+    filter: Option<filter::Filter>,
+    audit: AuditLog,
+    policy: policy::PolicyLists,
+    store: Store,
+    /// When each user last sent a message in each room, for slow mode.
+    last_message_at: HashMap<(RoomId, UserId), Instant>,
+    templates: BTreeMap<String, RoomTemplate>,
+    /// Open Q&A sessions.
+    qa: qa::QaSessions,
+    /// Open polls.
+    polls: polls::Polls,
+    welcome: welcome::Welcome,
+    /// Direct message rooms and the user each is with.
+    direct_rooms: HashMap<RoomId, UserId>,
+    /// Rooms attendees can ask the bot to invite them to.
+    open_rooms: Vec<RoomId>,
+    /// Ticket codes attendees can redeem for access to rooms.
+    tickets: Option<tickets::Tickets>,
+    schedule: Option<ScheduleConfig>,
+    /// The rate-limited queue all invites go through.
+    invites: InviteQueue,
+    /// The managed rooms, as shared with the API.
+    managed: ManagedRooms,
 }
 
 impl State {
@@ -104,15 +252,30 @@ impl State {
         log::trace!("Response: {:#?}", sync);
         let mut state_change = false;
 
+        if let Some(direct) = direct::from_account_data(&sync.account_data.events) {
+            self.update_direct_rooms(direct);
+        }
+
         // Immediately accept new room invitations and retry pending invites.
         state_change |= self.handle_invites(sync.rooms.invite).await;
 
         // Only look at rooms the user hasn't left yet
-        state_change |= self.handle_rooms(sync.rooms.join, handle_messages).await;
+        let mut rooms = sync.rooms.join;
+        // Policy rules need to be known before checking members of other rooms.
+        self.handle_policy_rooms(&mut rooms, handle_messages).await;
+        state_change |= self.handle_rooms(rooms, handle_messages).await;
+
+        // Forget rooms the bot left or was removed from.
+        state_change |= self.handle_left_rooms(sync.rooms.leave).await;
+        self.managed.set(self.managed_rooms());
 
         // If any room state changed, relay that information to the backend.
         if state_change {
-            if let Err(e) = backend::rooms(&self.strapi_client, &self.all_room_info).await {
+            let rooms = self
+                .all_room_info
+                .iter()
+                .filter(|(room_id, _)| !self.direct_rooms.contains_key(room_id));
+            if let Err(e) = backend::rooms(&self.strapi_client, rooms).await {
                 log::error!("Failed to post room changes to the backend. Error: {:?}", e);
             }
         }
@@ -126,10 +289,22 @@ impl State {
     /// Returns `false` otherwise.
     async fn handle_invites(&mut self, invites: BTreeMap<RoomId, InvitedRoom>) -> bool {
         // First insert new invites to be tried.
-        for (room_id, _) in invites {
+        let mut new_direct = false;
+        for (room_id, invite) in invites {
+            if let Some(user_id) = direct::inviter(&invite, &self.bot_id) {
+                log::info!("Invited to a direct message room by {}", user_id);
+                self.direct_rooms.insert(room_id.clone(), user_id);
+                new_direct = true;
+            }
             // 4 = try once immediately, retry up to 3 times.
             self.pending_invites.insert(room_id, 4);
         }
+        if new_direct {
+            let rooms = self.direct_rooms_by_user();
+            if let Err(e) = matrix::set_direct_rooms(&self.client, &self.bot_id, &rooms).await {
+                log::error!("Failed to update the direct message rooms. Error: {:?}", e);
+            }
+        }
 
         let mut state_change = false;
         let mut to_delete = vec![];
@@ -170,6 +345,633 @@ impl State {
         }
         state_change
     }
+
+    /// Drop the rooms the bot left, or was kicked or banned from, from the state.
+    ///
+    /// The rooms are forgotten and their stored settings removed.
+    ///
+    /// Returns `true` if any room state changed.
+    /// Returns `false` otherwise.
+    async fn handle_left_rooms(&mut self, rooms: BTreeMap<RoomId, LeftRoom>) -> bool {
+        let mut state_change = false;
+        let mut direct_change = false;
+        for room_id in rooms.keys() {
+            log::info!("Left room {}", room_id);
+            state_change |= self.all_room_info.remove(room_id).is_some();
+            direct_change |= self.direct_rooms.remove(room_id).is_some();
+            self.pending_invites.remove(room_id);
+            self.qa.stop(room_id);
+            self.last_message_at
+                .retain(|(message_room, _), _| message_room != room_id);
+            if let Err(e) = self.store.update(|data| data.remove_room(room_id)) {
+                log::error!(
+                    "(Room: {}) Failed to remove the room's settings. Error: {:?}",
+                    room_id,
+                    e
+                );
+            }
+            if let Err(e) = matrix::forget_room(&self.client, room_id).await {
+                log::warn!(
+                    "(Room: {}) Failed to forget the room. Error: {:?}",
+                    room_id,
+                    e
+                );
+            }
+        }
+
+        if direct_change {
+            let rooms = self.direct_rooms_by_user();
+            if let Err(e) = matrix::set_direct_rooms(&self.client, &self.bot_id, &rooms).await {
+                log::error!("Failed to update the direct message rooms. Error: {:?}", e);
+            }
+        }
+
+        state_change
+    }
+
+    /// Move to the room replacing a room after it was upgraded.
+    ///
+    /// Joins the new room, carries over all settings and leaves the old room.
+    /// The old room is dropped from the state once the leave comes back in a sync.
+    ///
+    /// Returns `true` if the new room was joined.
+    /// Returns `false` otherwise.
+    async fn follow_tombstone(&mut self, old_room: &RoomId, new_room: &RoomId) -> bool {
+        log::info!("(Room: {}) Room was replaced by {}", old_room, new_room);
+        if let Err(e) = matrix::join_room(&self.client, new_room.as_str()).await {
+            log::error!(
+                "(Room: {}) Failed to join the room replacing it. Error: {:?}",
+                old_room,
+                e
+            );
+            return false;
+        }
+
+        if let Err(e) = self.store.update(|data| data.move_room(old_room, new_room)) {
+            log::error!(
+                "(Room: {}) Failed to move the room's settings. Error: {:?}",
+                old_room,
+                e
+            );
+        }
+        for open_room in &mut self.open_rooms {
+            if open_room == old_room {
+                *open_room = new_room.clone();
+            }
+        }
+        if self.mod_room.as_ref() == Some(old_room) {
+            self.mod_room = Some(new_room.clone());
+        }
+        self.welcome.move_room(old_room, new_room);
+        if let Some(tickets) = &mut self.tickets {
+            tickets.move_room(old_room, new_room);
+        }
+        if let Some(user_id) = self.direct_rooms.remove(old_room) {
+            self.direct_rooms.insert(new_room.clone(), user_id);
+            let rooms = self.direct_rooms_by_user();
+            if let Err(e) = matrix::set_direct_rooms(&self.client, &self.bot_id, &rooms).await {
+                log::error!("Failed to update the direct message rooms. Error: {:?}", e);
+            }
+        }
+        self.all_room_info
+            .entry(new_room.clone())
+            .or_insert_with(|| RoomInfo {
+                id: new_room.as_str().into(),
+                ..Default::default()
+            });
+
+        let record = Record::new(self.bot_id.as_str(), "follow-tombstone")
+            .target(new_room)
+            .room(old_room);
+        self.audit.record(record).await;
+
+        if let Err(e) = matrix::leave_room(&self.client, old_room).await {
+            log::warn!(
+                "(Room: {}) Failed to leave the replaced room. Error: {:?}",
+                old_room,
+                e
+            );
+        }
+        true
+    }
+
+    /// Update the ban rules from the followed policy rooms.
+    ///
+    /// Policy rooms are removed from `rooms`, they are not managed like other rooms.
+    /// New and removed rules are enforced if `enforce` is set.
+    /// Otherwise rules are only loaded and applied to users joining later.
+    async fn handle_policy_rooms(
+        &mut self,
+        rooms: &mut BTreeMap<RoomId, JoinedRoom>,
+        enforce: bool,
+    ) {
+        let policy_room_ids = rooms
+            .keys()
+            .filter(|room_id| self.policy.is_policy_room(room_id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for room_id in policy_room_ids {
+            let room = match rooms.remove(&room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+
+            let state = room.state.events.iter().map(|e| e.deserialize_as());
+            let timeline = room.timeline.events.iter().map(|e| e.deserialize_as());
+            for event in state.chain(timeline).flatten() {
+                let change = match self.policy.update(&room_id, event) {
+                    Some(change) => change,
+                    None => continue,
+                };
+                if !enforce {
+                    continue;
+                }
+                match change {
+                    policy::Change::Added(rule) => self.apply_policy_rule(&rule).await,
+                    policy::Change::Removed(rule) => self.lift_policy_rule(&rule).await,
+                }
+            }
+        }
+    }
+
+    /// Ban all users matching a new policy rule from every managed room.
+    async fn apply_policy_rule(&self, rule: &policy::Rule) {
+        log::info!("New policy rule for {}: {}", rule.entity, rule.reason);
+        let rooms = self.managed_rooms();
+
+        let mut targets: HashMap<UserId, Vec<RoomId>> = HashMap::new();
+        if let Some(user_id) = rule.literal_user() {
+            targets.insert(user_id, rooms);
+        } else {
+            for room_id in rooms {
+                let members = match matrix::joined_members(&self.client, &room_id).await {
+                    Ok(members) => members,
+                    Err(e) => {
+                        log::error!("Failed to get members of {}. Error: {:?}", room_id, e);
+                        continue;
+                    }
+                };
+                for user_id in members.into_iter().filter(|u| rule.matches(u)) {
+                    targets.entry(user_id).or_default().push(room_id.clone());
+                }
+            }
+        }
+
+        let reason = rule.ban_reason();
+        for (user_id, rooms) in targets {
+            if self.is_protected(&user_id) {
+                continue;
+            }
+            moderation::everywhere(
+                &self.client,
+                &self.audit,
+                &self.store,
+                self.bot_id.as_str(),
+                &self.bot_id,
+                &rooms,
+                &user_id,
+                Action::Ban,
+                Some(&reason),
+            )
+            .await;
+        }
+    }
+
+    /// Lift the bans applied because of a removed policy rule.
+    ///
+    /// Looks through the banned users of every managed room,
+    /// so bans applied before the bot restarted are lifted as well.
+    /// Only bans applied by a policy rule are lifted, users still matching another rule stay banned.
+    async fn lift_policy_rule(&self, rule: &policy::Rule) {
+        log::info!("Removed policy rule for {}", rule.entity);
+
+        for room_id in self.managed_rooms() {
+            let banned = match matrix::banned_users(&self.client, &room_id).await {
+                Ok(banned) => banned,
+                Err(e) => {
+                    log::error!("Failed to get banned users of {}. Error: {:?}", room_id, e);
+                    continue;
+                }
+            };
+
+            for (user_id, reason) in banned {
+                let banned_by_policy =
+                    reason.is_some_and(|r| r.starts_with(policy::BAN_REASON_PREFIX));
+                if !banned_by_policy
+                    || !rule.matches(&user_id)
+                    || self.policy.matching(&user_id).is_some()
+                {
+                    continue;
+                }
+
+                match matrix::unban_user(&self.client, &room_id, &user_id).await {
+                    Ok(()) => {
+                        let record = Record::new(self.bot_id.as_str(), "unban")
+                            .target(&user_id)
+                            .room(&room_id)
+                            .reason(Some(format!("policy rule for {} removed", rule.entity)));
+                        self.audit.record(record).await;
+                    }
+                    Err(e) => log::error!("Failed to unban {}. Error: {:?}", user_id, e),
+                }
+            }
+        }
+    }
+
+    /// Ban a user from a room if they match a policy rule.
+    async fn enforce_policy(&self, room_id: &RoomId, user_id: &UserId) {
+        if self.is_protected(user_id) {
+            return;
+        }
+        let reason = match self.policy.matching(user_id) {
+            Some(rule) => rule.ban_reason(),
+            None => return,
+        };
+
+        moderation::everywhere(
+            &self.client,
+            &self.audit,
+            &self.store,
+            self.bot_id.as_str(),
+            &self.bot_id,
+            std::slice::from_ref(room_id),
+            user_id,
+            Action::Ban,
+            Some(&reason),
+        )
+        .await;
+    }
+
+    /// Check whether a user must never be banned automatically.
+    ///
+    /// This is the bot itself and all bot admins.
+    fn is_protected(&self, user_id: &UserId) -> bool {
+        *user_id == self.bot_id || self.admin_users.iter().any(|u| u == user_id.as_str())
+    }
+
+    /// Check whether a user is exempt from automatic moderation in a room.
+    ///
+    /// Bot admins and users with at least moderator power level (50) are exempt.
+    fn is_moderator(&self, room_info: &RoomInfo, user_id: &UserId) -> bool {
+        if self.is_protected(user_id) {
+            return true;
+        }
+
+        match &room_info.power_levels {
+            Some(levels) => moderation::user_level(levels, user_id) >= Int::from(50),
+            None => false,
+        }
+    }
+
+    /// Send a notice to the moderators.
+    ///
+    /// Uses the moderation room if configured, otherwise the room the notice is about.
+    async fn notify_moderators(&self, room_id: &RoomId, msg: String) {
+        let target = self.mod_room.as_ref().unwrap_or(room_id);
+        if let Err(e) = matrix::send_message(&self.client, target, msg).await {
+            log::error!("Failed to notify moderators. Error: {:?}", e);
+        }
+    }
+
+    /// Send a private message to a user.
+    ///
+    /// Uses an existing direct message room with the user if there is one,
+    /// opens one otherwise.
+    async fn direct_message(&self, user_id: &UserId, msg: String) -> anyhow::Result<()> {
+        let existing = self
+            .store
+            .read(|data| data.direct_rooms.get(user_id).cloned())
+            .or_else(|| {
+                self.direct_rooms
+                    .iter()
+                    .find(|(_, user)| *user == user_id)
+                    .map(|(room_id, _)| room_id.clone())
+            });
+        let room_id = match existing {
+            Some(room_id) => room_id,
+            None => {
+                let room_id = matrix::create_direct_room(&self.client, user_id).await?;
+                self.store
+                    .update(|data| data.direct_rooms.insert(user_id.clone(), room_id.clone()))?;
+
+                // The new room shows up in the next sync's `m.direct`.
+                let mut rooms = self.direct_rooms_by_user();
+                rooms
+                    .entry(user_id.clone())
+                    .or_default()
+                    .push(room_id.clone());
+                matrix::set_direct_rooms(&self.client, &self.bot_id, &rooms).await?;
+                room_id
+            }
+        };
+        matrix::send_message(&self.client, &room_id, msg).await
+    }
+
+    /// The rooms the bot manages.
+    ///
+    /// These are all joined rooms except for direct message rooms and the moderation log room.
+    /// Policy rooms are never part of the room information.
+    fn managed_rooms(&self) -> Vec<RoomId> {
+        self.all_room_info
+            .keys()
+            .filter(|room_id| !self.is_direct(room_id))
+            .filter(|room_id| self.audit.room() != Some(*room_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Check whether a room is a direct message room.
+    fn is_direct(&self, room_id: &RoomId) -> bool {
+        self.direct_rooms.contains_key(room_id)
+    }
+
+    /// The direct message rooms by the user they are with, as in `m.direct`.
+    fn direct_rooms_by_user(&self) -> BTreeMap<UserId, Vec<RoomId>> {
+        let mut rooms: BTreeMap<UserId, Vec<RoomId>> = BTreeMap::new();
+        for (room_id, user_id) in &self.direct_rooms {
+            rooms
+                .entry(user_id.clone())
+                .or_default()
+                .push(room_id.clone());
+        }
+        rooms
+    }
+
+    /// Replace the known direct message rooms with the `m.direct` account data.
+    ///
+    /// Rooms the bot opened itself are kept.
+    fn update_direct_rooms(&mut self, rooms: BTreeMap<UserId, Vec<RoomId>>) {
+        self.direct_rooms = self.store.read(|data| {
+            data.direct_rooms
+                .iter()
+                .map(|(user_id, room_id)| (room_id.clone(), user_id.clone()))
+                .collect()
+        });
+        for (user_id, room_ids) in rooms {
+            for room_id in room_ids {
+                self.direct_rooms.insert(room_id, user_id.clone());
+            }
+        }
+    }
+
+    /// Send the questions of a room's Q&A to the backend.
+    async fn post_questions(&self, room_id: &RoomId) {
+        let (open, questions) = match self.qa.get(room_id) {
+            Some(session) => (true, session.top()),
+            None => (false, vec![]),
+        };
+        if let Err(e) = backend::questions(&self.strapi_client, room_id, open, questions).await {
+            log::error!("Failed to post questions to the backend. Error: {:?}", e);
+        }
+    }
+
+    /// Welcome a user who just joined a room.
+    ///
+    /// Nothing is sent if the room has no welcome message
+    /// or too many users joined the room recently.
+    async fn welcome(&self, room_info: &RoomInfo, room_id: &RoomId, user_id: &UserId, name: &str) {
+        if *user_id == self.bot_id || self.is_direct(room_id) {
+            return;
+        }
+        let welcome = match self.welcome.message(room_id, &self.templates, &self.store) {
+            Some(welcome) => welcome,
+            None => return,
+        };
+        if !self.welcome.allow(room_id) {
+            log::info!("Not welcoming {} to {}, too many joins.", user_id, room_id);
+            return;
+        }
+
+        let room = room_info
+            .name
+            .as_deref()
+            .unwrap_or(room_info.display_name());
+        let msg = self.welcome.render(&welcome.message, name, user_id, room);
+        let sent = match welcome.via {
+            WelcomeVia::Notice => matrix::send_notice(&self.client, room_id, msg).await,
+            WelcomeVia::Direct => self.direct_message(user_id, msg).await,
+        };
+        if let Err(e) = sent {
+            log::error!(
+                "Failed to welcome {} to {}. Error: {:?}",
+                user_id,
+                room_id,
+                e
+            );
+        }
+    }
+
+    /// Count a reaction as a vote.
+    async fn handle_reaction(&mut self, room_id: &RoomId, reaction: &reactions::Reaction) {
+        if reaction.sender == self.bot_id {
+            return;
+        }
+        if self.qa.vote(room_id, reaction) {
+            self.post_questions(room_id).await;
+        }
+        if let Some(results) = self.polls.vote(room_id, reaction) {
+            self.post_poll(results).await;
+        }
+    }
+
+    /// Take back the vote of a redacted reaction.
+    async fn handle_redaction(&mut self, room_id: &RoomId, redacts: &EventId) {
+        if self.qa.unvote(room_id, redacts) {
+            self.post_questions(room_id).await;
+        }
+        if let Some(results) = self.polls.unvote(room_id, redacts) {
+            self.post_poll(results).await;
+        }
+    }
+
+    /// Send the current results of a poll to the backend.
+    async fn post_poll(&self, results: polls::Results) {
+        if let Err(e) = backend::poll(&self.strapi_client, results).await {
+            log::error!("Failed to post poll results to the backend. Error: {:?}", e);
+        }
+    }
+
+    /// Check whether a user is posting faster than the room's slow mode allows.
+    ///
+    /// Only allowed messages count towards the interval.
+    fn slowmode_violated(&mut self, room_id: &RoomId, user_id: &UserId) -> Option<u64> {
+        let interval = self
+            .store
+            .read(|data| data.slowmode.get(room_id).copied())?;
+        let key = (room_id.clone(), user_id.clone());
+        let now = Instant::now();
+
+        if let Some(last) = self.last_message_at.get(&key) {
+            if now.duration_since(*last) < Duration::from_secs(interval) {
+                return Some(interval);
+            }
+        }
+        self.last_message_at.insert(key, now);
+        None
+    }
+
+    /// Check a message against slow mode, the flood detection and the filter and enforce them.
+    ///
+    /// Moderators are exempt.
+    ///
+    /// Returns `true` if the message was caught and should not be handled any further.
+    /// Returns `false` otherwise.
+    async fn enforce(
+        &mut self,
+        room_info: &RoomInfo,
+        room_id: &RoomId,
+        msg: &SyncMessageEvent<MessageEventContent>,
+    ) -> bool {
+        if self.is_moderator(room_info, &msg.sender) {
+            return false;
+        }
+
+        if let Some(interval) = self.slowmode_violated(room_id, &msg.sender) {
+            let reason = "slow mode";
+            let redacted =
+                matrix::redact_event(&self.client, room_id, &msg.event_id, Some(reason)).await;
+            if let Err(e) = redacted {
+                log::error!("Failed to redact message in slow mode. Error: {:?}", e);
+            }
+            let warning = format!(
+                "Slow mode is enabled in {}. You can send one message every {} seconds.",
+                room_info.display_name(),
+                interval
+            );
+            if let Err(e) = self.direct_message(&msg.sender, warning).await {
+                log::error!("Failed to warn {}. Error: {:?}", msg.sender, e);
+            }
+            return true;
+        }
+
+        let (body, formatted) = match text_content(&msg.content.msgtype) {
+            Some(text) => text,
+            None => return false,
+        };
+
+        let flooding = self.flood.as_mut().and_then(|flood| {
+            flood
+                .check(room_id, &msg.sender, body)
+                .map(|reason| (reason, flood.mute_duration()))
+        });
+        if let Some((reason, duration)) = flooding {
+            self.auto_mute(room_info, room_id, &msg.sender, duration, reason)
+                .await;
+            return true;
+        }
+
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return false,
+        };
+        let reason = match filter
+            .check(body)
+            .or_else(|| formatted.and_then(|formatted| filter.check(formatted)))
+        {
+            Some(reason) => reason,
+            None => return false,
+        };
+        let record = Record::new(self.bot_id.as_str(), "filter")
+            .target(&msg.sender)
+            .room(room_info.display_name())
+            .reason(Some(&reason));
+        self.audit.record(record).await;
+
+        for action in &filter.actions {
+            match action {
+                FilterAction::Redact => {
+                    let redacted =
+                        matrix::redact_event(&self.client, room_id, &msg.event_id, Some(&reason))
+                            .await;
+                    if let Err(e) = redacted {
+                        log::error!("Failed to redact filtered message. Error: {:?}", e);
+                    }
+                }
+                FilterAction::Warn => {
+                    let warning = format!(
+                        "{}: Your message was removed by the filter ({}). Please follow the code of conduct.",
+                        msg.sender, reason
+                    );
+                    if let Err(e) = matrix::send_message(&self.client, room_id, warning).await {
+                        log::error!("Failed to warn {}. Error: {:?}", msg.sender, e);
+                    }
+                }
+                FilterAction::Mute => {
+                    self.auto_mute(room_info, room_id, &msg.sender, filter.mute, &reason)
+                        .await;
+                }
+                FilterAction::Report => {
+                    let report = format!(
+                        "Filtered message from {} in {} ({}): {}",
+                        msg.sender,
+                        room_info.display_name(),
+                        reason,
+                        body
+                    );
+                    self.notify_moderators(room_id, report).await;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Mute a user in a room for the given duration.
+    ///
+    /// The user is unmuted again once the duration passed, see [`moderation::unmute_expired`].
+    async fn auto_mute(
+        &self,
+        room_info: &RoomInfo,
+        room_id: &RoomId,
+        user_id: &UserId,
+        duration: Duration,
+        reason: &str,
+    ) {
+        let muted =
+            moderation::mute_for(&self.client, &self.store, room_id, user_id, duration).await;
+        if let Err(e) = muted {
+            log::error!("Failed to mute {}. Error: {:?}", user_id, e);
+            return;
+        }
+
+        let record = Record::new(self.bot_id.as_str(), "mute")
+            .target(user_id)
+            .room(room_info.display_name())
+            .reason(Some(format!("{} ({} seconds)", reason, duration.as_secs())));
+        self.audit.record(record).await;
+
+        let msg = format!(
+            "Muted {} in {} for {} seconds: {}",
+            user_id,
+            room_info.display_name(),
+            duration.as_secs(),
+            reason
+        );
+        self.notify_moderators(room_id, msg).await;
+    }
+}
+
+/// Get the body and the formatted body, if any, of a text, emote or notice message.
+///
+/// Returns `None` for other kinds of messages.
+fn text_content(msgtype: &MessageType) -> Option<(&str, Option<&str>)> {
+    let (body, formatted) = match msgtype {
+        MessageType::Text(TextMessageEventContent {
+            body, formatted, ..
+        }) => (body, formatted),
+        MessageType::Emote(EmoteMessageEventContent {
+            body, formatted, ..
+        }) => (body, formatted),
+        MessageType::Notice(NoticeMessageEventContent {
+            body, formatted, ..
+        }) => (body, formatted),
+        _ => return None,
+    };
+    Some((
+        body,
+        formatted.as_ref().map(|formatted| &formatted.body[..]),
+    ))
 }
 
 /// Join the room by invitiation.
@@ -221,8 +1023,15 @@ async fn handle_room_events(
     let mut entry = real_entry.clone();
 
     let mut state = false;
-    for event in events.into_iter().flat_map(|r| r.deserialize()) {
-        state |= handle_statechange(bot_state, &mut entry, room_id, event).await;
+    for event in events {
+        let create = event.deserialize_as::<spaces::CreateEvent>();
+        if let Some(is_space) = create.ok().and_then(|create| create.is_space()) {
+            entry.is_space = is_space;
+            state = true;
+        }
+        if let Ok(event) = event.deserialize() {
+            state |= handle_statechange(bot_state, &mut entry, room_id, event).await;
+        }
     }
 
     bot_state.all_room_info.insert(room_id.clone(), entry);
@@ -234,11 +1043,12 @@ async fn handle_room_events(
 /// This may change the room info state.
 /// If new users join the room and they are in the admin user group,
 /// they will be given appropriate permissions.
+/// If they match a policy rule, they will be banned.
 ///
 /// Returns `true` if any room state changed.
 /// Returns `false` otherwise.
 async fn handle_statechange(
-    bot_state: &State,
+    bot_state: &mut State,
     entry: &mut RoomInfo,
     room_id: &RoomId,
     state: AnySyncStateEvent,
@@ -262,6 +1072,34 @@ async fn handle_statechange(
             entry.topic = Some(topic);
             true
         }
+        AnySyncStateEvent::RoomPowerLevels(state) => {
+            log::debug!("(Room: {}) Received power levels", room_id);
+            entry.power_levels = Some(state.content);
+            false
+        }
+        AnySyncStateEvent::Custom(state) if state.content.event_type == "m.space.child" => {
+            log::debug!(
+                "(Room: {}) Received space child {}",
+                room_id,
+                state.state_key
+            );
+            spaces::update_children(&mut entry.children, state.state_key, &state.content.data);
+            true
+        }
+        AnySyncStateEvent::Custom(state) if state.content.event_type == "m.space.parent" => {
+            log::debug!(
+                "(Room: {}) Received space parent {}",
+                room_id,
+                state.state_key
+            );
+            spaces::update_parents(&mut entry.parents, state.state_key, &state.content.data);
+            true
+        }
+        AnySyncStateEvent::RoomTombstone(state) => {
+            bot_state
+                .follow_tombstone(room_id, &state.content.replacement_room)
+                .await
+        }
         AnySyncStateEvent::RoomMember(SyncStateEvent {
             content: member,
             sender,
@@ -283,7 +1121,17 @@ async fn handle_statechange(
                         .map(|u| UserId::try_from(&u[..]).unwrap())
                         .collect::<Vec<_>>();
                     users.push(bot_state.bot_id.clone());
-                    let _ = matrix::op_user(&bot_state.client, room_id, &users).await;
+                    if matrix::op_user(&bot_state.client, room_id, &users)
+                        .await
+                        .is_ok()
+                    {
+                        let record = Record::new(bot_state.bot_id.as_str(), "op")
+                            .target(&sender)
+                            .room(entry.display_name());
+                        bot_state.audit.record(record).await;
+                    }
+                } else {
+                    bot_state.enforce_policy(room_id, &sender).await;
                 }
             }
             false
@@ -299,8 +1147,10 @@ async fn handle_statechange(
 ///
 /// This will:
 ///
-/// * Relay room messages to the backend.
+/// * Relay room messages to the backend, except direct messages.
+/// * Count reactions as votes.
 /// * Handle any room state change.
+/// * Welcome users joining the room.
 ///
 /// Returns `true` if any room state changed.
 /// Returns `false` otherwise.
@@ -312,7 +1162,18 @@ async fn handle_timeline(
 ) -> bool {
     let mut roomstate = false;
 
-    for event in events.into_iter().flat_map(|r| r.deserialize()) {
+    for raw in events {
+        if let Some(reaction) = reactions::Reaction::parse(&raw) {
+            log::trace!("Room: {:?}, Reaction: {:?}", room_id, reaction);
+            if handle_messages {
+                bot_state.handle_reaction(room_id, &reaction).await;
+            }
+            continue;
+        }
+        let event = match raw.deserialize() {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
         log::trace!("Room: {:?}, Event: {:?}", room_id, event);
         let real_entry = bot_state
             .all_room_info
@@ -324,46 +1185,54 @@ async fn handle_timeline(
         let mut entry = real_entry.clone();
 
         match event {
+            AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomRedaction(redaction))
+                if handle_messages =>
+            {
+                bot_state
+                    .handle_redaction(room_id, &redaction.redacts)
+                    .await;
+            }
             AnySyncRoomEvent::Message(msg) if handle_messages => {
                 // Send all message events to the backend server.
                 if let AnySyncMessageEvent::RoomMessage(msg) = msg {
-                    if let Err(e) =
-                        backend::post(&bot_state.strapi_client, &entry, &room_id, &msg).await
-                    {
-                        log::error!("Failed to post to the backend. Error: {:?}", e);
+                    // Caught messages are kept away from the backend and never run as commands.
+                    if bot_state.enforce(&entry, room_id, &msg).await {
+                        continue;
                     }
 
-                    if let SyncMessageEvent {
-                        content:
-                            MessageEventContent {
-                                msgtype:
-                                    MessageType::Text(TextMessageEventContent {
-                                        body: msg_body, ..
-                                    }),
-                                ..
-                            },
-                        sender,
-                        ..
-                    } = msg
-                    {
-                        // Handle commands from room messages
-                        if let Err(e) = messages::handle(
-                            &bot_state.bot_id,
-                            &bot_state.client,
-                            &room_id,
-                            &sender,
-                            &msg_body,
-                            &mut bot_state.admin_users,
-                        )
-                        .await
+                    // Direct messages and reports are private.
+                    if !bot_state.is_direct(room_id) && !messages::is_private(&msg) {
+                        if let Err(e) =
+                            backend::post(&bot_state.strapi_client, &entry, room_id, &msg).await
                         {
-                            log::error!("Failed to handle message. Error: {:?}", e);
+                            log::error!("Failed to post to the backend. Error: {:?}", e);
                         }
                     }
+
+                    // Handle commands from room messages
+                    if let Err(e) = messages::handle(bot_state, room_id, &msg).await {
+                        log::error!("Failed to handle message. Error: {:?}", e);
+                    }
                 }
             }
             AnySyncRoomEvent::State(state) => {
+                let joined = match &state {
+                    AnySyncStateEvent::RoomMember(member) if handle_messages => {
+                        match member.membership_change() {
+                            MembershipChange::Joined => {
+                                Some((member.sender.clone(), member.content.displayname.clone()))
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
                 roomstate |= handle_statechange(bot_state, &mut entry, room_id, state).await;
+
+                if let Some((user_id, name)) = joined {
+                    let name = name.as_deref().unwrap_or(user_id.localpart());
+                    bot_state.welcome(&entry, room_id, &user_id, name).await;
+                }
             }
             _ => log::debug!("Unhandled event: {:?}", event),
         }
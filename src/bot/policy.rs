@@ -0,0 +1,165 @@
+//! Moderation policy lists
+//!
+//! Policy rooms contain `m.policy.rule.user` and `m.policy.rule.server` state events,
+//! each recommending to ban matching users or servers.
+//! See <https://spec.matrix.org/unstable/client-server-api/#moderation-policy-lists>.
+
+use crate::moderation::glob_to_regex;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use regex::Regex;
+use ruma::{RoomId, UserId};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+/// Prefix of the reason for every ban applied because of a policy rule.
+///
+/// Only bans with this prefix are lifted again when a rule is removed.
+pub const BAN_REASON_PREFIX: &str = "Policy: ";
+
+/// Event types of user rules, including the older names still in use.
+const USER_RULE_TYPES: &[&str] = &[
+    "m.policy.rule.user",
+    "m.room.rule.user",
+    "org.matrix.mjolnir.rule.user",
+];
+
+/// Event types of server rules, including the older names still in use.
+const SERVER_RULE_TYPES: &[&str] = &[
+    "m.policy.rule.server",
+    "m.room.rule.server",
+    "org.matrix.mjolnir.rule.server",
+];
+
+/// Identifies a rule: the policy room, the event type and the state key.
+type RuleKey = (RoomId, String, String);
+
+/// What a rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    User,
+    Server,
+}
+
+/// A single ban rule.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub kind: Kind,
+    /// The user ID or server name glob.
+    pub entity: String,
+    pub reason: String,
+    matcher: Regex,
+}
+
+impl Rule {
+    /// Check whether the rule applies to a user.
+    pub fn matches(&self, user_id: &UserId) -> bool {
+        match self.kind {
+            Kind::User => self.matcher.is_match(user_id.as_str()),
+            Kind::Server => self.matcher.is_match(user_id.server_name().as_str()),
+        }
+    }
+
+    /// Whether the rule is about exactly one user, without any glob.
+    pub fn literal_user(&self) -> Option<UserId> {
+        if self.kind != Kind::User || self.entity.contains(['*', '?']) {
+            return None;
+        }
+        UserId::try_from(&self.entity[..]).ok()
+    }
+
+    /// The reason to give when banning a user because of this rule.
+    pub fn ban_reason(&self) -> String {
+        format!("{}{}", BAN_REASON_PREFIX, self.reason)
+    }
+}
+
+/// A state event in a policy room.
+#[derive(Deserialize)]
+pub struct PolicyEvent {
+    #[serde(rename = "type")]
+    typ: String,
+    state_key: Option<String>,
+    content: JsonValue,
+}
+
+#[derive(Deserialize)]
+struct RuleContent {
+    entity: String,
+    recommendation: String,
+    #[serde(default)]
+    reason: String,
+}
+
+/// A change to the set of rules.
+pub enum Change {
+    Added(Rule),
+    Removed(Rule),
+}
+
+/// All rules of the followed policy rooms.
+#[derive(Default)]
+pub struct PolicyLists {
+    rooms: HashSet<RoomId>,
+    rules: HashMap<RuleKey, Rule>,
+}
+
+impl PolicyLists {
+    /// Follow the given policy rooms.
+    pub fn new(rooms: HashSet<RoomId>) -> Self {
+        PolicyLists {
+            rooms,
+            ..Default::default()
+        }
+    }
+
+    /// Check whether a room is a followed policy room.
+    pub fn is_policy_room(&self, room_id: &RoomId) -> bool {
+        self.rooms.contains(room_id)
+    }
+
+    /// Update the rules from a state event in a policy room.
+    ///
+    /// A rule with empty content or an unsupported recommendation removes the previous rule.
+    /// Returns the change, if any.
+    pub fn update(&mut self, room_id: &RoomId, event: PolicyEvent) -> Option<Change> {
+        let kind = if USER_RULE_TYPES.contains(&&event.typ[..]) {
+            Kind::User
+        } else if SERVER_RULE_TYPES.contains(&&event.typ[..]) {
+            Kind::Server
+        } else {
+            return None;
+        };
+        let key = (room_id.clone(), event.typ, event.state_key?);
+
+        let rule = serde_json::from_value::<RuleContent>(event.content)
+            .ok()
+            .filter(|content| content.recommendation == "m.ban")
+            .and_then(|content| {
+                let matcher = glob_to_regex(&content.entity)?;
+                Some(Rule {
+                    kind,
+                    entity: content.entity,
+                    reason: content.reason,
+                    matcher,
+                })
+            });
+
+        match rule {
+            Some(rule) => {
+                self.rules.insert(key, rule.clone());
+                Some(Change::Added(rule))
+            }
+            None => {
+                let rule = self.rules.remove(&key)?;
+                Some(Change::Removed(rule))
+            }
+        }
+    }
+
+    /// Find a rule that applies to the user.
+    pub fn matching(&self, user_id: &UserId) -> Option<&Rule> {
+        self.rules.values().find(|rule| rule.matches(user_id))
+    }
+}
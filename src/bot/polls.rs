@@ -0,0 +1,290 @@
+//! Polls
+//!
+//! A poll is a message listing numbered options.
+//! Users vote by reacting with an option's number, one vote per user.
+//! Polls close after a set time and post their results.
+
+use crate::{matrix, strapi};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ruma::{EventId, RoomId, UserId};
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+use serde::Serialize;
+
+use super::{backend, reactions::Reaction};
+
+/// The reactions to vote for each option with.
+pub const OPTIONS: [&str; 10] = ["1️⃣", "2️⃣", "3️⃣", "4️⃣", "5️⃣", "6️⃣", "7️⃣", "8️⃣", "9️⃣", "🔟"];
+
+/// How long polls are open unless set otherwise.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// The longest a poll can be open.
+pub const MAX_DURATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// An open poll.
+#[derive(Debug)]
+pub struct Poll {
+    room_id: RoomId,
+    event_id: EventId,
+    question: String,
+    options: Vec<String>,
+    closes_at: DateTime<Utc>,
+    /// Each user's vote: the reaction's event ID and the option.
+    votes: HashMap<UserId, (EventId, usize)>,
+}
+
+/// The results of a poll, as sent to the backend.
+#[derive(Serialize, Debug)]
+pub struct Results {
+    channel_id: String,
+    poll_id: String,
+    question: String,
+    options: Vec<OptionResult>,
+    open: bool,
+    closes_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+struct OptionResult {
+    option: String,
+    votes: usize,
+}
+
+impl Poll {
+    /// The poll message, listing the options.
+    pub fn message(question: &str, options: &[String], closes_at: DateTime<Utc>) -> String {
+        let mut msg = format!("📊 {}\n", question);
+        for (option, key) in options.iter().zip(OPTIONS.iter()) {
+            msg.push_str(&format!("{} {}\n", key, option));
+        }
+        msg.push_str(&format!(
+            "React to vote. The poll closes at {} UTC.",
+            closes_at.format("%H:%M")
+        ));
+        msg
+    }
+
+    /// The current results.
+    fn results(&self, open: bool) -> Results {
+        let mut votes = vec![0; self.options.len()];
+        for (_, option) in self.votes.values() {
+            votes[*option] += 1;
+        }
+        Results {
+            channel_id: self.room_id.as_str().into(),
+            poll_id: self.event_id.as_str().into(),
+            question: self.question.clone(),
+            options: self
+                .options
+                .iter()
+                .zip(votes)
+                .map(|(option, votes)| OptionResult {
+                    option: option.clone(),
+                    votes,
+                })
+                .collect(),
+            open,
+            closes_at: self.closes_at,
+        }
+    }
+}
+
+impl Results {
+    /// The message announcing the final results.
+    fn message(&self) -> String {
+        let total = self
+            .options
+            .iter()
+            .map(|option| option.votes)
+            .sum::<usize>();
+        let mut msg = format!("📊 Poll closed: {}\n", self.question);
+        for (option, key) in self.options.iter().zip(OPTIONS.iter()) {
+            let percent = match total {
+                0 => 0,
+                total => option.votes * 100 / total,
+            };
+            msg.push_str(&format!(
+                "{} {}: {} votes ({}%)\n",
+                key, option.option, option.votes, percent
+            ));
+        }
+        msg.push_str(&format!("{} votes in total.", total));
+        msg
+    }
+}
+
+/// The option a reaction votes for, if any.
+///
+/// Some clients send the number keycaps without the emoji variation selector.
+fn option(key: &str, options: usize) -> Option<usize> {
+    let key = key.replace('\u{fe0f}', "");
+    OPTIONS
+        .iter()
+        .take(options)
+        .position(|option| option.replace('\u{fe0f}', "") == key)
+}
+
+/// All open polls, by the event ID of their message.
+///
+/// Shared with the tasks closing the polls.
+#[derive(Clone, Default)]
+pub struct Polls {
+    polls: Arc<Mutex<HashMap<EventId, Poll>>>,
+}
+
+impl Polls {
+    /// Post a poll to a room and open it for votes.
+    ///
+    /// The poll is closed and its results posted after `duration`.
+    pub async fn open(
+        &self,
+        client: &Client,
+        strapi_client: &strapi::Client,
+        room_id: &RoomId,
+        question: String,
+        options: Vec<String>,
+        duration: Duration,
+    ) -> anyhow::Result<()> {
+        let closes_at = Utc::now()
+            .checked_add_signed(chrono::Duration::from_std(duration)?)
+            .ok_or_else(|| anyhow::anyhow!("poll duration out of range"))?;
+        let msg = Poll::message(&question, &options, closes_at);
+        let event_id = matrix::post_message(client, room_id, msg).await?;
+        for key in OPTIONS.iter().take(options.len()) {
+            matrix::send_reaction(client, room_id, &event_id, key).await?;
+        }
+
+        let poll = Poll {
+            room_id: room_id.clone(),
+            event_id: event_id.clone(),
+            question,
+            options,
+            closes_at,
+            votes: HashMap::new(),
+        };
+        let results = poll.results(true);
+        self.polls.lock().unwrap().insert(event_id.clone(), poll);
+        backend::poll(strapi_client, results).await?;
+
+        let polls = self.clone();
+        let client = client.clone();
+        let strapi_client = strapi_client.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            if let Err(e) = polls.close(&client, &strapi_client, &event_id).await {
+                log::error!("Failed to close poll {}. Error: {:?}", event_id, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Close a poll and post its results.
+    async fn close(
+        &self,
+        client: &Client,
+        strapi_client: &strapi::Client,
+        event_id: &EventId,
+    ) -> anyhow::Result<()> {
+        let poll = match self.polls.lock().unwrap().remove(event_id) {
+            Some(poll) => poll,
+            None => return Ok(()),
+        };
+        let results = poll.results(false);
+        matrix::send_message(client, &poll.room_id, results.message()).await?;
+        backend::poll(strapi_client, results).await
+    }
+
+    /// Count a reaction as a vote if it is an option of an open poll.
+    ///
+    /// A new vote replaces the user's previous vote.
+    /// Returns the new results if the votes changed.
+    pub fn vote(&self, room_id: &RoomId, reaction: &Reaction) -> Option<Results> {
+        let mut polls = self.polls.lock().unwrap();
+        let poll = polls.get_mut(reaction.target())?;
+        if poll.room_id != *room_id {
+            return None;
+        }
+        let option = option(reaction.key(), poll.options.len())?;
+        poll.votes
+            .insert(reaction.sender.clone(), (reaction.event_id.clone(), option));
+        Some(poll.results(true))
+    }
+
+    /// Take back the vote of a redacted reaction.
+    ///
+    /// Returns the new results if the votes changed.
+    pub fn unvote(&self, room_id: &RoomId, reaction_id: &EventId) -> Option<Results> {
+        let mut polls = self.polls.lock().unwrap();
+        let poll = polls
+            .values_mut()
+            .filter(|poll| poll.room_id == *room_id)
+            .find(|poll| poll.votes.values().any(|(id, _)| id == reaction_id))?;
+        poll.votes.retain(|_, (id, _)| id != reaction_id);
+        Some(poll.results(true))
+    }
+}
+
+/// Parse how long a poll is open, like `90s`, `5m` or `1h`.
+///
+/// Polls are open for at least a second and at most `MAX_DURATION`.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let (amount, unit_secs) = if let Some(amount) = duration.strip_suffix('s') {
+        (amount, 1)
+    } else if let Some(amount) = duration.strip_suffix('m') {
+        (amount, 60)
+    } else if let Some(amount) = duration.strip_suffix('h') {
+        (amount, 60 * 60)
+    } else {
+        return None;
+    };
+    let amount: u64 = amount.parse().ok()?;
+    let duration = Duration::from_secs(amount.checked_mul(unit_secs)?);
+    if duration.as_secs() == 0 || duration > MAX_DURATION {
+        return None;
+    }
+    Some(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_duration() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(60 * 60)));
+        assert_eq!(parse_duration("168h"), Some(MAX_DURATION));
+    }
+
+    #[test]
+    fn reject_invalid_duration() {
+        for duration in &[
+            "",
+            "s",
+            "0s",
+            "0m",
+            "5",
+            "5d",
+            "5é",
+            "é",
+            "-5m",
+            "1.5h",
+            "169h",
+            "18446744073709551615h",
+            "99999999999999999999s",
+        ] {
+            assert_eq!(
+                parse_duration(duration),
+                None,
+                "{:?} should not parse",
+                duration
+            );
+        }
+    }
+}
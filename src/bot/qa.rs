@@ -0,0 +1,160 @@
+//! Q&A sessions
+//!
+//! While Q&A is open in a room, questions are collected from messages starting with `Q:`
+//! and from `!ask`. Attendees vote for questions with 👍 reactions.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use ruma::{EventId, RoomId, UserId};
+use serde::Serialize;
+
+use super::reactions::Reaction;
+
+/// The reaction counted as a vote.
+const VOTE: char = '👍';
+
+/// A collected question.
+#[derive(Clone, Debug, Serialize)]
+pub struct Question {
+    /// The event ID of the message asking the question.
+    pub event_id: EventId,
+    /// Who asked the question.
+    pub sender: UserId,
+    /// The question.
+    pub question: String,
+    /// Who voted for the question.
+    #[serde(skip)]
+    voters: HashSet<UserId>,
+    /// The number of votes.
+    pub votes: usize,
+}
+
+/// The questions of a room.
+#[derive(Default, Debug)]
+pub struct Session {
+    questions: Vec<Question>,
+    /// Votes by their reaction's event ID, to take them back when the reaction is redacted.
+    votes: HashMap<EventId, (EventId, UserId)>,
+}
+
+impl Session {
+    /// The questions, most votes first, earlier questions first on ties.
+    pub fn top(&self) -> Vec<Question> {
+        let mut questions = self.questions.clone();
+        questions.sort_by_key(|question| Reverse(question.votes));
+        questions
+    }
+}
+
+/// Open Q&A sessions, per room.
+#[derive(Default)]
+pub struct QaSessions {
+    sessions: HashMap<RoomId, Session>,
+}
+
+impl QaSessions {
+    /// Open Q&A in a room.
+    ///
+    /// Returns `false` if it was already open.
+    pub fn start(&mut self, room_id: &RoomId) -> bool {
+        if self.sessions.contains_key(room_id) {
+            return false;
+        }
+        self.sessions.insert(room_id.clone(), Session::default());
+        true
+    }
+
+    /// Close Q&A in a room.
+    ///
+    /// Returns the session's questions, or `None` if Q&A was not open.
+    pub fn stop(&mut self, room_id: &RoomId) -> Option<Session> {
+        self.sessions.remove(room_id)
+    }
+
+    /// The Q&A session of a room, if open.
+    pub fn get(&self, room_id: &RoomId) -> Option<&Session> {
+        self.sessions.get(room_id)
+    }
+
+    /// Collect a question.
+    ///
+    /// Returns `false` if Q&A is not open in the room.
+    pub fn ask(
+        &mut self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        question: &str,
+    ) -> bool {
+        let session = match self.sessions.get_mut(room_id) {
+            Some(session) => session,
+            None => return false,
+        };
+        session.questions.push(Question {
+            event_id: event_id.clone(),
+            sender: sender.clone(),
+            question: question.to_string(),
+            voters: HashSet::new(),
+            votes: 0,
+        });
+        true
+    }
+
+    /// Count a reaction as a vote if it is a 👍 on a question.
+    ///
+    /// Every user has one vote per question.
+    /// Returns `true` if the votes changed.
+    pub fn vote(&mut self, room_id: &RoomId, reaction: &Reaction) -> bool {
+        if !reaction.key().starts_with(VOTE) {
+            return false;
+        }
+        let session = match self.sessions.get_mut(room_id) {
+            Some(session) => session,
+            None => return false,
+        };
+        let question = session
+            .questions
+            .iter_mut()
+            .find(|question| question.event_id == *reaction.target());
+        let question = match question {
+            Some(question) => question,
+            None => return false,
+        };
+        if !question.voters.insert(reaction.sender.clone()) {
+            return false;
+        }
+        question.votes = question.voters.len();
+        session.votes.insert(
+            reaction.event_id.clone(),
+            (question.event_id.clone(), reaction.sender.clone()),
+        );
+        true
+    }
+
+    /// Take back the vote of a redacted reaction.
+    ///
+    /// Returns `true` if the votes changed.
+    pub fn unvote(&mut self, room_id: &RoomId, reaction_id: &EventId) -> bool {
+        let session = match self.sessions.get_mut(room_id) {
+            Some(session) => session,
+            None => return false,
+        };
+        let (event_id, user_id) = match session.votes.remove(reaction_id) {
+            Some(vote) => vote,
+            None => return false,
+        };
+        let question = session
+            .questions
+            .iter_mut()
+            .find(|question| question.event_id == event_id);
+        match question {
+            Some(question) => {
+                question.voters.remove(&user_id);
+                question.votes = question.voters.len();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Reactions
+//!
+//! Reactions are `m.reaction` events annotating another event with a key, usually an emoji.
+//! See <https://github.com/matrix-org/matrix-doc/pull/2677>.
+
+use ruma::{EventId, UserId};
+use serde::Deserialize;
+
+/// A reaction to an event.
+///
+/// Reactions are not supported by ruma yet.
+#[derive(Deserialize, Debug)]
+pub struct Reaction {
+    #[serde(rename = "type")]
+    typ: String,
+    /// The reaction's own event ID, referenced when it is redacted.
+    pub event_id: EventId,
+    /// The user who reacted.
+    pub sender: UserId,
+    content: ReactionContent,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReactionContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Annotation,
+}
+
+#[derive(Deserialize, Debug)]
+struct Annotation {
+    rel_type: String,
+    event_id: EventId,
+    key: String,
+}
+
+impl Reaction {
+    /// Parse a reaction from a raw room event.
+    ///
+    /// Returns `None` if the event is not a reaction.
+    pub fn parse<T>(event: &ruma::serde::Raw<T>) -> Option<Self> {
+        let reaction = event.deserialize_as::<Reaction>().ok()?;
+        if reaction.typ != "m.reaction" || reaction.content.relates_to.rel_type != "m.annotation" {
+            return None;
+        }
+        Some(reaction)
+    }
+
+    /// The event reacted to.
+    pub fn target(&self) -> &EventId {
+        &self.content.relates_to.event_id
+    }
+
+    /// The reaction key, usually an emoji.
+    pub fn key(&self) -> &str {
+        &self.content.relates_to.key
+    }
+}
@@ -0,0 +1,95 @@
+//! Spaces
+//!
+//! Spaces group rooms into a hierarchy, e.g. conference, tracks and session rooms.
+//! A space lists its rooms in `m.space.child` state events and rooms point back to their spaces
+//! in `m.space.parent` state events.
+//! See <https://spec.matrix.org/unstable/client-server-api/#spaces>.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// A room within a space.
+#[derive(Clone, Debug, Serialize)]
+pub struct SpaceChild {
+    /// The room ID
+    pub room_id: String,
+    /// The position of the room within the space, if set.
+    pub order: Option<String>,
+}
+
+/// The `m.room.create` event, only for the room type.
+///
+/// The room type is not supported by ruma yet.
+#[derive(Deserialize)]
+pub struct CreateEvent {
+    #[serde(rename = "type")]
+    typ: String,
+    content: CreateContent,
+}
+
+#[derive(Deserialize)]
+struct CreateContent {
+    #[serde(rename = "type")]
+    room_type: Option<String>,
+}
+
+impl CreateEvent {
+    /// Whether this creates a space.
+    ///
+    /// Returns `None` if this is not an `m.room.create` event.
+    pub fn is_space(&self) -> Option<bool> {
+        if self.typ != "m.room.create" {
+            return None;
+        }
+        Some(self.content.room_type.as_deref() == Some("m.space"))
+    }
+}
+
+/// Update the children of a space from an `m.space.child` event.
+///
+/// Children without any `via` servers are removed.
+/// Children are sorted by their order first, then by their room ID.
+pub fn update_children(
+    children: &mut Vec<SpaceChild>,
+    room_id: String,
+    content: &BTreeMap<String, JsonValue>,
+) {
+    children.retain(|child| child.room_id != room_id);
+    if !has_via(content) {
+        return;
+    }
+
+    let order = content
+        .get("order")
+        .and_then(|order| order.as_str())
+        .map(str::to_string);
+    children.push(SpaceChild { room_id, order });
+    // Rooms with an order come first.
+    children.sort_by(|a, b| {
+        (a.order.is_none(), &a.order, &a.room_id).cmp(&(b.order.is_none(), &b.order, &b.room_id))
+    });
+}
+
+/// Update the spaces a room is in from an `m.space.parent` event.
+///
+/// Parents without any `via` servers are removed.
+pub fn update_parents(
+    parents: &mut Vec<String>,
+    space_id: String,
+    content: &BTreeMap<String, JsonValue>,
+) {
+    parents.retain(|parent| *parent != space_id);
+    if has_via(content) {
+        parents.push(space_id);
+        parents.sort();
+    }
+}
+
+fn has_via(content: &BTreeMap<String, JsonValue>) -> bool {
+    content
+        .get("via")
+        .and_then(|via| via.as_array())
+        .is_some_and(|via| !via.is_empty())
+}
@@ -0,0 +1,213 @@
+//! Ticket codes
+//!
+//! Attendees redeem their ticket code with `!join <code>` in a direct message with the bot
+//! and are invited to the rooms of their ticket type.
+//! Codes are looked up in the backend or in a local CSV or JSON allow list.
+
+use crate::{config::TicketsConfig, matrix, store::Store, strapi};
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use governor::{clock::DefaultClock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use ruma::{RoomId, UserId};
+use serde::Deserialize;
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+
+/// How many codes a user can try per minute, to keep codes from being guessed.
+const ATTEMPTS_PER_MINUTE: u32 = 5;
+
+/// A ticket, as listed by the backend or the allow list.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Ticket {
+    /// The code attendees redeem.
+    pub code: String,
+    /// The ticket type, deciding which rooms the ticket gives access to.
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// How often the code can be redeemed, if not the configured default.
+    pub max_uses: Option<u32>,
+}
+
+/// Where ticket codes are looked up.
+enum Source {
+    /// The backend endpoint listing tickets.
+    Backend(String),
+    /// The allow list, by code.
+    List(HashMap<String, Ticket>),
+}
+
+/// The outcome of redeeming a ticket code.
+pub enum Redeemed {
+    /// The ticket is valid for these rooms.
+    Rooms(String, Vec<RoomId>),
+    /// The code is not a valid ticket.
+    Invalid,
+    /// The code was already redeemed as often as allowed.
+    UsedUp,
+    /// The user tried too many codes recently.
+    TooManyAttempts,
+}
+
+/// Ticket codes and the rooms each ticket type gives access to.
+pub struct Tickets {
+    source: Source,
+    types: HashMap<String, Vec<RoomId>>,
+    max_uses: u32,
+    /// Limits the attempts per user.
+    limiter: RateLimiter<UserId, DefaultKeyedStateStore<UserId>, DefaultClock>,
+}
+
+impl Tickets {
+    /// Set up ticket codes from the configuration.
+    pub async fn new(client: &Client, config: TicketsConfig) -> anyhow::Result<Self> {
+        let source = match (config.endpoint, config.file) {
+            (Some(endpoint), None) => Source::Backend(endpoint),
+            (None, Some(file)) => Source::List(load(&file)?),
+            _ => bail!("direct.tickets needs either an endpoint or a file"),
+        };
+
+        let mut types = HashMap::new();
+        for (typ, rooms) in config.types {
+            let mut room_ids = vec![];
+            for room in rooms {
+                room_ids.push(matrix::real_room_id(client, &room).await?);
+            }
+            types.insert(typ, room_ids);
+        }
+
+        let per_minute = NonZeroU32::new(ATTEMPTS_PER_MINUTE).unwrap();
+        Ok(Tickets {
+            source,
+            types,
+            max_uses: config.max_uses,
+            limiter: RateLimiter::keyed(Quota::per_minute(per_minute)),
+        })
+    }
+
+    /// Give access to the room replacing a room instead.
+    pub fn move_room(&mut self, from: &RoomId, to: &RoomId) {
+        for room_id in self.types.values_mut().flatten() {
+            if room_id == from {
+                *room_id = to.clone();
+            }
+        }
+    }
+
+    /// Look up a ticket by its code.
+    async fn lookup(
+        &self,
+        strapi_client: &strapi::Client,
+        code: &str,
+    ) -> anyhow::Result<Option<Ticket>> {
+        match &self.source {
+            Source::List(tickets) => Ok(tickets.get(code).cloned()),
+            Source::Backend(endpoint) => {
+                let path = format!(
+                    "{}?code={}",
+                    endpoint,
+                    utf8_percent_encode(code, NON_ALPHANUMERIC)
+                );
+                let tickets: Vec<Ticket> = strapi::get(strapi_client, &path).await?;
+                Ok(tickets.into_iter().find(|ticket| ticket.code == code))
+            }
+        }
+    }
+
+    /// Redeem a ticket code for a user.
+    ///
+    /// Every use by another user counts against the code's limit.
+    /// Users can redeem a code they already redeemed again, e.g. after leaving a room.
+    pub async fn redeem(
+        &self,
+        strapi_client: &strapi::Client,
+        store: &Store,
+        user_id: &UserId,
+        code: &str,
+    ) -> anyhow::Result<Redeemed> {
+        if self.limiter.check_key(user_id).is_err() {
+            return Ok(Redeemed::TooManyAttempts);
+        }
+
+        let ticket = match self.lookup(strapi_client, code).await? {
+            Some(ticket) => ticket,
+            None => return Ok(Redeemed::Invalid),
+        };
+        let rooms = match self.types.get(&ticket.typ) {
+            Some(rooms) => rooms.clone(),
+            None => {
+                log::warn!("No rooms configured for ticket type {}", ticket.typ);
+                return Ok(Redeemed::Invalid);
+            }
+        };
+
+        let max_uses = ticket.max_uses.unwrap_or(self.max_uses) as usize;
+        let redeemed = store.update(|data| {
+            let users = data.ticket_uses.entry(ticket.code.clone()).or_default();
+            if users.contains(user_id) {
+                true
+            } else if users.len() < max_uses {
+                users.push(user_id.clone());
+                true
+            } else {
+                false
+            }
+        })?;
+        if !redeemed {
+            return Ok(Redeemed::UsedUp);
+        }
+
+        Ok(Redeemed::Rooms(ticket.typ, rooms))
+    }
+}
+
+/// Load the allow list.
+///
+/// JSON files hold a list of tickets.
+/// Any other file is read as CSV, one `code,type[,max_uses]` per line.
+fn load(file: &Path) -> anyhow::Result<HashMap<String, Ticket>> {
+    let content = fs::read_to_string(file)
+        .with_context(|| format!("Can't read ticket file {}", file.display()))?;
+
+    let tickets: Vec<Ticket> = if file.extension() == Some("json".as_ref()) {
+        serde_json::from_str(&content)?
+    } else {
+        content
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(n, line)| {
+                parse_csv_line(line)
+                    .ok_or_else(|| anyhow!("Invalid ticket on line {}: {}", n, line))
+            })
+            .collect::<anyhow::Result<_>>()?
+    };
+
+    Ok(tickets
+        .into_iter()
+        .map(|ticket| (ticket.code.clone(), ticket))
+        .collect())
+}
+
+/// Parse a `code,type[,max_uses]` line.
+fn parse_csv_line(line: &str) -> Option<Ticket> {
+    let mut fields = line.split(',').map(str::trim);
+    let code = fields.next().filter(|code| !code.is_empty())?;
+    let typ = fields.next().filter(|typ| !typ.is_empty())?;
+    let max_uses = match fields.next() {
+        Some(max_uses) => Some(max_uses.parse().ok()?),
+        None => None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(Ticket {
+        code: code.to_string(),
+        typ: typ.to_string(),
+        max_uses,
+    })
+}
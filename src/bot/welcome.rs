@@ -0,0 +1,91 @@
+//! Welcome messages
+//!
+//! Users joining a room are welcomed with the room's message,
+//! the message of the template the room was created from or the default message.
+
+use crate::{
+    config::{RoomTemplate, WelcomeConfig, WelcomeMessage},
+    matrix,
+    store::Store,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+
+use anyhow::anyhow;
+use governor::{clock::DefaultClock, state::keyed::DefaultKeyedStateStore, Quota, RateLimiter};
+use ruma::{RoomId, UserId};
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+
+/// Welcome messages for all rooms.
+pub struct Welcome {
+    /// The message for rooms without their own.
+    default: Option<WelcomeMessage>,
+    /// Messages for single rooms.
+    rooms: HashMap<RoomId, WelcomeMessage>,
+    /// The link to the code of conduct.
+    code_of_conduct: Option<String>,
+    /// Limits the welcome messages per room.
+    limiter: RateLimiter<RoomId, DefaultKeyedStateStore<RoomId>, DefaultClock>,
+}
+
+impl Welcome {
+    /// Set up the welcome messages from the configuration.
+    pub async fn new(client: &Client, config: WelcomeConfig) -> anyhow::Result<Self> {
+        let per_minute = NonZeroU32::new(config.per_minute)
+            .ok_or_else(|| anyhow!("welcome.per_minute must be greater than 0"))?;
+
+        let mut rooms = HashMap::new();
+        for (room, message) in config.rooms {
+            rooms.insert(matrix::real_room_id(client, &room).await?, message);
+        }
+        let via = config.via;
+        let default = config
+            .message
+            .map(|message| WelcomeMessage { message, via });
+
+        Ok(Welcome {
+            default,
+            rooms,
+            code_of_conduct: config.code_of_conduct,
+            limiter: RateLimiter::keyed(Quota::per_minute(per_minute)),
+        })
+    }
+
+    /// The message to welcome users to a room with, if any.
+    pub fn message(
+        &self,
+        room_id: &RoomId,
+        templates: &BTreeMap<String, RoomTemplate>,
+        store: &Store,
+    ) -> Option<WelcomeMessage> {
+        if let Some(message) = self.rooms.get(room_id) {
+            return Some(message.clone());
+        }
+        let template = store.read(|data| data.room_templates.get(room_id).cloned());
+        template
+            .and_then(|name| templates.get(&name))
+            .and_then(|template| template.welcome.clone())
+            .or_else(|| self.default.clone())
+    }
+
+    /// Move a room's message to the room replacing it.
+    pub fn move_room(&mut self, from: &RoomId, to: &RoomId) {
+        if let Some(message) = self.rooms.remove(from) {
+            self.rooms.insert(to.clone(), message);
+        }
+    }
+
+    /// Check whether another welcome message may be sent in a room right now.
+    pub fn allow(&self, room_id: &RoomId) -> bool {
+        self.limiter.check_key(room_id).is_ok()
+    }
+
+    /// Fill in the placeholders of a message.
+    pub fn render(&self, message: &str, name: &str, user_id: &UserId, room: &str) -> String {
+        message
+            .replace("{name}", name)
+            .replace("{user}", user_id.as_str())
+            .replace("{room}", room)
+            .replace("{coc}", self.code_of_conduct.as_deref().unwrap_or(""))
+    }
+}
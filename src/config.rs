@@ -1,8 +1,15 @@
-use std::{fs, net::SocketAddr, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use http::Uri;
+use ruma::{MxcUri, RoomVersionId};
 use serde::{de, Deserialize};
+use serde_json::{Map as JsonMap, Value as JsonValue};
 
 #[derive(Deserialize)]
 pub struct Configuration {
@@ -14,6 +21,37 @@ pub struct Configuration {
 
     /// Configuration for the backend
     pub backend: BackendConfig,
+
+    /// Configuration for moderation features.
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+
+    /// Configuration for persisting runtime settings.
+    #[serde(default)]
+    pub store: StoreConfig,
+
+    /// Named templates for new rooms.
+    #[serde(default)]
+    pub templates: BTreeMap<String, RoomTemplate>,
+
+    /// Configuration for syncing the schedule from the backend.
+    pub schedule: Option<ScheduleConfig>,
+
+    /// Configuration for scheduled announcements.
+    #[serde(default)]
+    pub announcements: AnnouncementsConfig,
+
+    /// Configuration for welcoming users joining rooms.
+    #[serde(default)]
+    pub welcome: WelcomeConfig,
+
+    /// Configuration for direct messages with the bot.
+    #[serde(default)]
+    pub direct: DirectConfig,
+
+    /// Configuration for inviting users.
+    #[serde(default)]
+    pub invites: InvitesConfig,
 }
 
 #[derive(Deserialize)]
@@ -39,6 +77,12 @@ pub struct ApiConfig {
 
     /// The API secret.
     pub secret: String,
+
+    /// Additional named API secrets.
+    ///
+    /// The name is recorded in the audit trail for every action taken with the secret.
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
 }
 
 #[derive(Deserialize)]
@@ -49,10 +93,341 @@ pub struct BackendConfig {
     pub password: String,
 }
 
+#[derive(Deserialize, Default)]
+pub struct ModerationConfig {
+    /// The room to send moderation notices to.
+    pub room: Option<String>,
+
+    /// The room to post a record of every privileged action to.
+    pub log_room: Option<String>,
+
+    /// The file to append a record of every privileged action to.
+    pub audit_file: Option<PathBuf>,
+
+    /// Flood detection. Disabled if not set.
+    pub flood: Option<FloodConfig>,
+
+    /// Word and link filter. Disabled if not set.
+    pub filter: Option<FilterConfig>,
+
+    /// Policy rooms (IDs or aliases) to follow and enforce the ban rules of.
+    #[serde(default)]
+    pub policy_rooms: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FloodConfig {
+    /// The maximum number of messages a user can send within `window`.
+    pub messages: u32,
+
+    /// The length of the window in seconds.
+    pub window: u64,
+
+    /// The number of identical messages in a row that are considered spam.
+    pub repeats: u32,
+
+    /// How long a flooding user is muted, in seconds.
+    pub mute: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FilterConfig {
+    /// Words to block, matched case-insensitively as whole words.
+    #[serde(default)]
+    pub words: Vec<String>,
+
+    /// Regular expressions to block.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+
+    /// Link domains to block, including their subdomains.
+    #[serde(default)]
+    pub deny_domains: Vec<String>,
+
+    /// Link domains to allow, including their subdomains.
+    /// If not empty, links to any other domain are blocked.
+    #[serde(default)]
+    pub allow_domains: Vec<String>,
+
+    /// What to do with filtered messages.
+    pub actions: Vec<FilterAction>,
+
+    /// How long to mute users sending filtered messages, in seconds.
+    #[serde(default = "default_filter_mute")]
+    pub mute: u64,
+}
+
+fn default_filter_mute() -> u64 {
+    600
+}
+
+/// An action to take on a filtered message.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Redact the message.
+    Redact,
+    /// Warn the user in the room.
+    Warn,
+    /// Mute the user.
+    Mute,
+    /// Report the message to the moderators.
+    Report,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ScheduleConfig {
+    /// The backend endpoint listing all sessions.
+    pub endpoint: String,
+    /// How often to check the schedule, in seconds.
+    #[serde(default = "default_schedule_interval")]
+    pub interval: u64,
+    /// Rooms (IDs or aliases) for the room names used by sessions.
+    #[serde(default)]
+    pub rooms: BTreeMap<String, String>,
+}
+
+fn default_schedule_interval() -> u64 {
+    60
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementsConfig {
+    /// How late an announcement may still be sent, in seconds.
+    /// Announcements missed by more, e.g. while the bot was down, are skipped.
+    #[serde(default = "default_announcements_grace")]
+    pub grace: u64,
+}
+
+impl Default for AnnouncementsConfig {
+    fn default() -> Self {
+        AnnouncementsConfig {
+            grace: default_announcements_grace(),
+        }
+    }
+}
+
+fn default_announcements_grace() -> u64 {
+    300
+}
+
+#[derive(Deserialize)]
+pub struct WelcomeConfig {
+    /// The message for rooms without their own, if any.
+    pub message: Option<String>,
+    /// How to send the message for rooms without their own.
+    #[serde(default)]
+    pub via: WelcomeVia,
+    /// The link to the code of conduct, for the `{coc}` placeholder.
+    pub code_of_conduct: Option<String>,
+    /// How many welcome messages to send per room per minute at most.
+    #[serde(default = "default_welcome_per_minute")]
+    pub per_minute: u32,
+    /// Messages for rooms (IDs or aliases).
+    #[serde(default)]
+    pub rooms: BTreeMap<String, WelcomeMessage>,
+}
+
+impl Default for WelcomeConfig {
+    fn default() -> Self {
+        WelcomeConfig {
+            message: None,
+            via: WelcomeVia::default(),
+            code_of_conduct: None,
+            per_minute: default_welcome_per_minute(),
+            rooms: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_welcome_per_minute() -> u32 {
+    10
+}
+
+/// A welcome message for users joining a room.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WelcomeMessage {
+    /// The message, with the placeholders `{name}`, `{user}`, `{room}` and `{coc}`.
+    pub message: String,
+    /// How to send the message.
+    #[serde(default)]
+    pub via: WelcomeVia,
+}
+
+/// How to send a welcome message.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WelcomeVia {
+    /// As a notice in the room.
+    #[default]
+    Notice,
+    /// As a direct message to the user.
+    Direct,
+}
+
+#[derive(Deserialize, Default, Clone)]
+pub struct DirectConfig {
+    /// Rooms (IDs or aliases) attendees can ask the bot to invite them to.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Ticket codes attendees can redeem for access to rooms.
+    pub tickets: Option<TicketsConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TicketsConfig {
+    /// The backend endpoint to look up ticket codes at.
+    pub endpoint: Option<String>,
+    /// A CSV or JSON file listing the valid ticket codes, instead of the backend.
+    pub file: Option<PathBuf>,
+    /// How often each code can be redeemed, unless the ticket says otherwise.
+    #[serde(default = "default_ticket_max_uses")]
+    pub max_uses: u32,
+    /// Rooms (IDs or aliases) per ticket type.
+    #[serde(default)]
+    pub types: BTreeMap<String, Vec<String>>,
+}
+
+fn default_ticket_max_uses() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct InvitesConfig {
+    /// How many invites to send per second at most.
+    #[serde(default = "default_invites_per_second")]
+    pub per_second: u32,
+    /// The identity server for email invites: a hostname, or a URL for the bot to reach it over plain HTTP.
+    pub identity_server: Option<String>,
+    /// The access token for the identity server.
+    /// Without one the bot registers with the identity server itself.
+    pub identity_access_token: Option<String>,
+}
+
+impl Default for InvitesConfig {
+    fn default() -> Self {
+        InvitesConfig {
+            per_second: default_invites_per_second(),
+            identity_server: None,
+            identity_access_token: None,
+        }
+    }
+}
+
+fn default_invites_per_second() -> u32 {
+    2
+}
+
+#[derive(Deserialize, Default)]
+pub struct StoreConfig {
+    /// The file to keep runtime settings in.
+    pub file: Option<PathBuf>,
+}
+
+/// Settings for new rooms.
+///
+/// Unset fields fall back to a private, invite-only room with shared history that guests can join.
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct RoomTemplate {
+    /// Whether the room is listed in the room directory.
+    pub visibility: Option<RoomVisibility>,
+    /// Who can join the room.
+    pub join_rules: Option<JoinRules>,
+    /// Who can read the room's history.
+    pub history_visibility: Option<HistoryVisibility>,
+    /// Whether guests can join the room.
+    pub guest_access: Option<GuestAccess>,
+    /// Whether to enable end-to-end encryption.
+    ///
+    /// The bot cannot read encrypted messages, so `true` is rejected when parsing the configuration.
+    #[serde(default)]
+    pub encryption: bool,
+    /// Power levels overriding the server's defaults, in the format of `m.room.power_levels`.
+    pub power_levels: Option<JsonMap<String, JsonValue>>,
+    /// The room's topic, unless a topic is given when creating the room.
+    pub topic: Option<String>,
+    /// The `mxc://` URL of the room's avatar.
+    pub avatar: Option<MxcUri>,
+    /// The room version. Uses the server's default if unset.
+    pub room_version: Option<RoomVersionId>,
+    /// The message to welcome users joining rooms created from this template.
+    pub welcome: Option<WelcomeMessage>,
+}
+
+/// The template used when no template is named.
+pub const DEFAULT_TEMPLATE: &str = "default";
+
+/// Find the template to create a room with.
+///
+/// Without a name, the template named [`DEFAULT_TEMPLATE`] is used if there is one.
+pub fn find_template(
+    templates: &BTreeMap<String, RoomTemplate>,
+    name: Option<&str>,
+) -> Result<RoomTemplate> {
+    match name {
+        Some(name) => templates
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("unknown room template {}", name)),
+        None => Ok(templates.get(DEFAULT_TEMPLATE).cloned().unwrap_or_default()),
+    }
+}
+
+/// Whether a room is listed in the room directory.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomVisibility {
+    Public,
+    Private,
+}
+
+/// Who can join a room.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinRules {
+    Public,
+    Invite,
+}
+
+/// Who can read a room's history.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryVisibility {
+    Invited,
+    Joined,
+    Shared,
+    WorldReadable,
+}
+
+/// Whether guests can join a room.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum GuestAccess {
+    CanJoin,
+    Forbidden,
+}
+
 /// Read the configuration from the provided file.
 pub fn parse<P: AsRef<Path>>(file: P) -> Result<Configuration> {
     let content = fs::read_to_string(file)?;
-    let cfg = toml::from_str(&content)?;
+    let cfg: Configuration = toml::from_str(&content)?;
+
+    // Commands, the filter, flood detection, the backend relay and polls all need to read messages.
+    for (name, template) in &cfg.templates {
+        if template.encryption {
+            anyhow::bail!(
+                "templates.{}: encryption is not supported, the bot cannot read messages in encrypted rooms",
+                name
+            );
+        }
+    }
+
+    if let Some(schedule) = &cfg.schedule {
+        if schedule.interval == 0 {
+            anyhow::bail!("schedule.interval must be greater than 0");
+        }
+    }
+
     Ok(cfg)
 }
 
@@ -0,0 +1,115 @@
+//! Identity server access.
+//!
+//! Email invites are stored by an identity server until the invitee signs up,
+//! and the homeserver needs an access token for it to do so.
+//! Unless a token is configured, the bot registers with the identity server
+//! using an OpenID token from its homeserver.
+
+use crate::matrix;
+
+use anyhow::bail;
+use ruma::UserId;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+
+#[derive(Deserialize)]
+struct RegisterResponse {
+    token: String,
+}
+
+/// An identity server and the bot's access token for it.
+pub struct IdentityServer {
+    /// The identity server's hostname and port, as passed to the homeserver.
+    id_server: String,
+    /// The URL to reach the identity server at.
+    base_url: String,
+    http: reqwest::Client,
+    bot_id: UserId,
+    /// The access token, once known.
+    token: Mutex<Option<String>>,
+}
+
+impl IdentityServer {
+    /// Set up access to an identity server.
+    ///
+    /// `server` is the identity server's hostname and port, reached over HTTPS,
+    /// or a URL like `http://localhost:8090` to reach it differently, e.g. for testing.
+    pub fn new(server: &str, token: Option<String>, bot_id: UserId) -> anyhow::Result<Self> {
+        let server = server.trim_end_matches('/');
+        let (id_server, base_url) = match server.split_once("://") {
+            Some((_, host)) => (host.to_string(), server.to_string()),
+            None => (server.to_string(), format!("https://{}", server)),
+        };
+        let http = reqwest::Client::builder()
+            .user_agent("waasabi-matrix/0.1.0")
+            .build()?;
+
+        Ok(IdentityServer {
+            id_server,
+            base_url,
+            http,
+            bot_id,
+            token: Mutex::new(token),
+        })
+    }
+
+    /// The identity server's hostname and port.
+    pub fn id_server(&self) -> &str {
+        &self.id_server
+    }
+
+    /// Get the bot's access token for the identity server, registering first if needed.
+    ///
+    /// The token is kept until it is rejected, see `forget_token`.
+    pub async fn access_token(&self, client: &Client) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = &*token {
+            return Ok(token.clone());
+        }
+
+        let openid = matrix::request_openid_token(client, &self.bot_id).await?;
+        let body = json!({
+            "access_token": openid.access_token,
+            "token_type": openid.token_type,
+            "matrix_server_name": openid.matrix_server_name,
+            "expires_in": openid.expires_in.as_secs(),
+        });
+        let res = self
+            .http
+            .post(format!(
+                "{}/_matrix/identity/v2/account/register",
+                self.base_url
+            ))
+            .json(&body)
+            .send()
+            .await?;
+        log::debug!("Response: {:?}", res);
+        if !res.status().is_success() {
+            bail!(
+                "Failed to register with identity server {}, status: {:?}",
+                self.id_server,
+                res.status()
+            );
+        }
+
+        let res: RegisterResponse = res.json().await?;
+        *token = Some(res.token.clone());
+        Ok(res.token)
+    }
+
+    /// Forget an access token the identity server rejected, so the next request registers again.
+    ///
+    /// Does nothing if the token was replaced in the meantime.
+    pub async fn forget_token(&self, rejected: &str) {
+        let mut token = self.token.lock().await;
+        if token.as_deref() == Some(rejected) {
+            log::warn!(
+                "Access token for identity server {} was rejected, registering again",
+                self.id_server
+            );
+            *token = None;
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Inviting users to rooms.
+//!
+//! All invites go through a queue limited to a configured rate,
+//! so onboarding hundreds of attendees at once does not run into the homeserver's rate limits.
+//! Users can be invited by their Matrix ID or, with an identity server configured, by email.
+
+use crate::{
+    audit::{AuditLog, Record},
+    config::InvitesConfig,
+    identity::IdentityServer,
+    matrix,
+};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use governor::{
+    clock::DefaultClock,
+    state::{direct::NotKeyed, InMemoryState},
+    Quota, RateLimiter,
+};
+use ruma::{events::room::member::MembershipState, RoomId, UserId};
+use serde::Serialize;
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+
+/// How often to retry an invite the homeserver rejected as rate-limited.
+const RETRIES: usize = 3;
+
+/// How long the results of a finished bulk invite are kept.
+const KEEP_RESULTS: Duration = Duration::from_secs(60 * 60);
+
+/// The outcome of inviting a user to a room.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The user was invited.
+    Invited,
+    /// The user already has a pending invite to the room.
+    AlreadyInvited,
+    /// The user already is a member of the room.
+    AlreadyJoined,
+    /// The user is banned from the room and was not invited.
+    Banned,
+    /// The invite failed.
+    Failed,
+}
+
+impl Status {
+    /// A description of the outcome, for use in replies.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Status::Invited => "invited",
+            Status::AlreadyInvited => "already invited",
+            Status::AlreadyJoined => "already joined",
+            Status::Banned => "banned",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// The outcome of inviting one user to one room.
+#[derive(Serialize, Clone, Debug)]
+pub struct InviteResult {
+    /// The user, as requested.
+    pub user_id: String,
+    /// The room, as requested.
+    pub room: String,
+    /// What happened.
+    pub status: Status,
+    /// Why the invite failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Someone to invite.
+#[derive(Debug)]
+pub enum Invitee {
+    /// A Matrix user.
+    User(UserId),
+    /// An email address, possibly without a Matrix account yet.
+    Email(String),
+}
+
+impl Invitee {
+    /// Parse a Matrix ID like `@user:homeserver` or an email address.
+    pub fn parse(invitee: &str) -> anyhow::Result<Self> {
+        if invitee.starts_with('@') {
+            Ok(Invitee::User(UserId::try_from(invitee)?))
+        } else if invitee.contains('@') {
+            Ok(Invitee::Email(invitee.to_string()))
+        } else {
+            bail!("not a user ID or email address: {}", invitee)
+        }
+    }
+}
+
+/// The rate-limited queue all invites go through.
+///
+/// Cloning it gives another handle to the same queue.
+#[derive(Clone)]
+pub struct InviteQueue {
+    limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
+    /// The identity server for email invites, if configured.
+    identity: Option<Arc<IdentityServer>>,
+    jobs: Arc<Mutex<BulkJobs>>,
+}
+
+impl InviteQueue {
+    /// Set up the queue from the configuration.
+    pub fn new(config: &InvitesConfig, bot_id: &UserId) -> anyhow::Result<Self> {
+        let per_second = NonZeroU32::new(config.per_second)
+            .ok_or_else(|| anyhow!("invites.per_second must be greater than 0"))?;
+        let identity = match &config.identity_server {
+            Some(server) => Some(Arc::new(IdentityServer::new(
+                server,
+                config.identity_access_token.clone(),
+                bot_id.clone(),
+            )?)),
+            None => None,
+        };
+        Ok(InviteQueue {
+            limiter: Arc::new(RateLimiter::direct(Quota::per_second(per_second))),
+            identity,
+            jobs: Arc::default(),
+        })
+    }
+
+    /// Invite a user or an email address to a room once it is their turn.
+    pub async fn invite_any(
+        &self,
+        client: &Client,
+        room_id: &RoomId,
+        invitee: &Invitee,
+    ) -> anyhow::Result<Status> {
+        match invitee {
+            Invitee::User(user_id) => self.invite(client, room_id, user_id).await,
+            Invitee::Email(email) => self.invite_email(client, room_id, email).await,
+        }
+    }
+
+    /// Invite an email address to a room once it is their turn.
+    ///
+    /// The invite shows up once the email address is bound to a Matrix account.
+    /// If the identity server rejects the bot's access token, the bot registers again and retries once.
+    /// Without a Matrix account there is no membership to check,
+    /// so email invites are always sent.
+    pub async fn invite_email(
+        &self,
+        client: &Client,
+        room_id: &RoomId,
+        email: &str,
+    ) -> anyhow::Result<Status> {
+        let identity = match &self.identity {
+            Some(identity) => identity,
+            None => bail!("no identity server configured for email invites"),
+        };
+        let token = identity.access_token(client).await?;
+
+        self.limiter.until_ready().await;
+        match matrix::invite_email(client, room_id, identity.id_server(), &token, email).await {
+            Ok(()) => {}
+            // The identity server forgot the token, e.g. after a restart. Register again once.
+            Err(e) if matrix::unauthorized(&e) => {
+                identity.forget_token(&token).await;
+                let token = identity.access_token(client).await?;
+                self.limiter.until_ready().await;
+                matrix::invite_email(client, room_id, identity.id_server(), &token, email).await?;
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(Status::Invited)
+    }
+
+    /// Invite a user to a room once it is their turn.
+    ///
+    /// The user's membership is checked first, so retried requests are harmless:
+    /// users who are already invited or joined are not invited again,
+    /// and banned users are never invited.
+    /// Invites rejected as rate-limited by the homeserver are retried after the requested delay.
+    pub async fn invite(
+        &self,
+        client: &Client,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> anyhow::Result<Status> {
+        match matrix::membership(client, room_id, user_id).await? {
+            Some(MembershipState::Invite) => return Ok(Status::AlreadyInvited),
+            Some(MembershipState::Join) => return Ok(Status::AlreadyJoined),
+            Some(MembershipState::Ban) => return Ok(Status::Banned),
+            _ => {}
+        }
+
+        let mut retries = 0;
+        loop {
+            self.limiter.until_ready().await;
+            match matrix::invite_user(client, room_id, user_id.as_str()).await {
+                Ok(()) => return Ok(Status::Invited),
+                Err(e) => match matrix::rate_limited(&e) {
+                    Some(delay) if retries < RETRIES => {
+                        log::warn!(
+                            "(Room: {}) Rate-limited inviting {}, retrying in {:?}",
+                            room_id,
+                            user_id,
+                            delay
+                        );
+                        retries += 1;
+                        tokio::time::sleep(delay).await;
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Start inviting each of the users, by Matrix ID or email, to each of the rooms.
+    ///
+    /// The invites are sent in the background, as a large batch takes a while at the configured rate.
+    /// Returns the ID to follow the job's progress with `bulk_job`.
+    pub fn start_bulk(
+        &self,
+        client: &Client,
+        audit: &AuditLog,
+        actor: &str,
+        users: Vec<String>,
+        rooms: Vec<String>,
+    ) -> u64 {
+        let id = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.prune();
+            jobs.next_id += 1;
+            let id = jobs.next_id;
+            let job = BulkJob {
+                total: users.len() * rooms.len(),
+                done: false,
+                results: Vec::with_capacity(users.len() * rooms.len()),
+                finished_at: None,
+            };
+            jobs.running.insert(id, job);
+            id
+        };
+
+        let queue = self.clone();
+        let client = client.clone();
+        let audit = audit.clone();
+        let actor = actor.to_string();
+        tokio::spawn(async move {
+            queue
+                .bulk(&client, &audit, &actor, id, &users, &rooms)
+                .await;
+        });
+        id
+    }
+
+    /// The progress of a bulk invite, unless it is unknown or finished long ago.
+    pub fn bulk_job(&self, id: u64) -> Option<BulkJob> {
+        self.jobs.lock().unwrap().running.get(&id).cloned()
+    }
+
+    /// Invite each of the users to each of the rooms, recording the outcomes in the job.
+    ///
+    /// A failure only affects its own user and room.
+    /// Every invite is recorded in the audit log on behalf of the actor.
+    async fn bulk(
+        &self,
+        client: &Client,
+        audit: &AuditLog,
+        actor: &str,
+        id: u64,
+        users: &[String],
+        rooms: &[String],
+    ) {
+        let mut room_ids = HashMap::new();
+        for room in rooms {
+            let room_id = matrix::real_room_id(client, room)
+                .await
+                .map_err(|e| e.to_string());
+            room_ids.insert(room, room_id);
+        }
+
+        for user in users {
+            let invitee = Invitee::parse(user).map_err(|e| e.to_string());
+            for room in rooms {
+                let outcome = match (&invitee, &room_ids[room]) {
+                    (Ok(invitee), Ok(room_id)) => self
+                        .invite_any(client, room_id, invitee)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    (Err(e), _) | (_, Err(e)) => Err(e.clone()),
+                };
+
+                let (status, error) = match outcome {
+                    Ok(status) => (status, None),
+                    Err(e) => {
+                        log::warn!("(Room: {}) Failed to invite {}. Error: {}", room, user, e);
+                        (Status::Failed, Some(e))
+                    }
+                };
+                if status == Status::Invited {
+                    let record = Record::new(actor, "invite").target(user).room(room);
+                    audit.record(record).await;
+                }
+                let result = InviteResult {
+                    user_id: user.clone(),
+                    room: room.clone(),
+                    status,
+                    error,
+                };
+                if let Some(job) = self.jobs.lock().unwrap().running.get_mut(&id) {
+                    job.results.push(result);
+                }
+            }
+        }
+
+        if let Some(job) = self.jobs.lock().unwrap().running.get_mut(&id) {
+            job.done = true;
+            job.finished_at = Some(Instant::now());
+        }
+        log::info!("Bulk invite {} done", id);
+    }
+}
+
+/// The bulk invites, by ID.
+#[derive(Default)]
+struct BulkJobs {
+    /// The ID of the last bulk invite.
+    next_id: u64,
+    running: HashMap<u64, BulkJob>,
+}
+
+impl BulkJobs {
+    /// Forget the results of bulk invites that finished a while ago.
+    fn prune(&mut self) {
+        self.running.retain(|_, job| match job.finished_at {
+            Some(finished_at) => finished_at.elapsed() < KEEP_RESULTS,
+            None => true,
+        });
+    }
+}
+
+/// A bulk invite and its progress.
+#[derive(Serialize, Clone, Debug)]
+pub struct BulkJob {
+    /// How many invites there are to send, one per user and room.
+    pub total: usize,
+    /// Whether every invite was sent.
+    pub done: bool,
+    /// The outcome for every user and room so far.
+    pub results: Vec<InviteResult>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
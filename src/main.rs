@@ -14,21 +14,32 @@
 //!   * Privileged users can create new channels and invite users.
 //! * Whatever additional command you want to implement.
 
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::env;
 use std::net::SocketAddr;
 use std::process;
+use std::time::Duration;
 
 use futures_util::future;
 use http::Uri;
 use ruma::{DeviceId, UserId};
 type RumaClient = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
 
+mod announcements;
 mod api;
+mod audit;
 mod bot;
 mod config;
+mod identity;
+mod invites;
 mod matrix;
+mod moderation;
+mod provision;
+mod schedule;
+mod store;
 mod strapi;
+mod upgrade;
 
 struct Config {
     matrix_homeserver: Uri,
@@ -41,17 +52,21 @@ struct Config {
     admin_users: Vec<String>,
     host: SocketAddr,
     api_secret: String,
+    api_tokens: BTreeMap<String, String>,
+    moderation: config::ModerationConfig,
+    store: store::Store,
+    templates: BTreeMap<String, config::RoomTemplate>,
+    schedule: Option<config::ScheduleConfig>,
+    announcements: config::AnnouncementsConfig,
+    welcome: config::WelcomeConfig,
+    direct: config::DirectConfig,
+    invites: config::InvitesConfig,
 }
 
-async fn matrix_bot(cfg: Config) -> anyhow::Result<()> {
-    let strapi_client = strapi::login(
-        &cfg.strapi_host,
-        &cfg.strapi_integrations_endpoint,
-        &cfg.strapi_user,
-        &cfg.strapi_password,
-    )
-    .await?;
-
+/// Log in to the Matrix server.
+///
+/// Returns the client and the bot's user ID.
+async fn matrix_login(cfg: &Config) -> anyhow::Result<(RumaClient, UserId)> {
     let client = RumaClient::new(cfg.matrix_homeserver.to_string(), None);
 
     // Once randomly chosen, this is now our ID.
@@ -67,17 +82,116 @@ async fn matrix_bot(cfg: Config) -> anyhow::Result<()> {
         )
         .await?;
     let bot_id = UserId::try_from(&cfg.matrix_username[..])?;
+
+    Ok((client, bot_id))
+}
+
+/// Set up the audit trail.
+async fn audit_log(cfg: &Config, client: &RumaClient) -> anyhow::Result<audit::AuditLog> {
+    let log_room = match &cfg.moderation.log_room {
+        Some(room) => Some(matrix::real_room_id(client, room).await?),
+        None => None,
+    };
+    let audit = audit::AuditLog::new(client.clone(), log_room, cfg.moderation.audit_file.clone());
+    Ok(audit)
+}
+
+async fn matrix_bot(cfg: Config) -> anyhow::Result<()> {
+    let strapi_client = strapi::login(
+        &cfg.strapi_host,
+        &cfg.strapi_integrations_endpoint,
+        &cfg.strapi_user,
+        &cfg.strapi_password,
+    )
+    .await?;
+
+    let (client, bot_id) = matrix_login(&cfg).await?;
+    let audit = audit_log(&cfg, &client).await?;
+    let invites = invites::InviteQueue::new(&cfg.invites, &bot_id)?;
+    let managed = bot::ManagedRooms::default();
+    let schedule = {
+        let client = client.clone();
+        let strapi_client = strapi_client.clone();
+        let schedule = cfg.schedule.clone();
+        async move {
+            match schedule {
+                Some(schedule) => schedule::sync(client, strapi_client, schedule).await,
+                None => Ok(()),
+            }
+        }
+    };
+    let announcements = announcements::run(
+        client.clone(),
+        cfg.store.clone(),
+        Duration::from_secs(cfg.announcements.grace),
+    );
+    let unmute = moderation::unmute_expired(client.clone(), cfg.store.clone());
     let bot = bot::event_loop(
-        bot_id,
+        bot_id.clone(),
         client.clone(),
         cfg.admin_users.clone(),
         strapi_client,
+        cfg.moderation,
+        audit.clone(),
+        cfg.store.clone(),
+        cfg.templates.clone(),
+        cfg.welcome,
+        cfg.direct,
+        cfg.schedule,
+        invites.clone(),
+        managed.clone(),
     );
 
-    let server = api::server(cfg.host, cfg.api_secret, cfg.admin_users, client);
-    let (bot_ended, server_ended) = future::join(bot, server).await;
+    let server = api::server(
+        cfg.host,
+        cfg.api_secret,
+        cfg.api_tokens,
+        bot_id,
+        cfg.admin_users,
+        client,
+        audit,
+        cfg.store,
+        cfg.templates,
+        invites,
+        managed,
+    );
+    let (bot_ended, server_ended, schedule_ended, announcements_ended, unmute_ended) =
+        future::join5(bot, server, schedule, announcements, unmute).await;
     bot_ended?;
     server_ended?;
+    schedule_ended?;
+    announcements_ended?;
+    unmute_ended?;
+
+    Ok(())
+}
+
+/// Reconcile the homeserver with a manifest and print the outcome.
+///
+/// The running bot does the provisioning, so its state is not overwritten.
+/// Exits with an error if provisioning any room failed.
+async fn provision(cfg: Config, manifest: &str, dry_run: bool) -> anyhow::Result<()> {
+    let manifest = provision::Manifest::load(manifest)?;
+    let outcomes = provision::through_api(cfg.host, &cfg.api_secret, &manifest, dry_run).await?;
+
+    if dry_run {
+        println!("Dry run, nothing changed.");
+    }
+    let mut failed = false;
+    for outcome in &outcomes {
+        let changes = match (&outcome.error, outcome.changes.is_empty()) {
+            (Some(e), _) => {
+                failed = true;
+                format!("failed ({})", e)
+            }
+            (None, true) => String::from("up to date"),
+            (None, false) => outcome.changes.join(", "),
+        };
+        println!("{}: {}", outcome.alias, changes);
+    }
+    if failed {
+        anyhow::bail!("provisioning failed for some rooms");
+    }
 
     Ok(())
 }
@@ -92,10 +206,11 @@ async fn main() -> anyhow::Result<()> {
         None => {
             eprintln!("Missing configuration file.");
             eprintln!();
-            eprintln!("Usage: waasabi-matrix <path to config file>");
+            eprintln!("Usage: waasabi-matrix <path to config file> [provision <path to manifest> [--dry-run]]");
             process::exit(1);
         }
     };
+    let command = args.collect::<Vec<_>>();
     let cfg = match config::parse(&cfg) {
         Ok(cfg) => cfg,
         Err(e) => {
@@ -115,6 +230,23 @@ async fn main() -> anyhow::Result<()> {
     let admin_users = cfg.matrix.admins;
     let host = cfg.api.listen;
     let api_secret = cfg.api.secret;
+    let api_tokens = cfg.api.tokens;
+    let moderation = cfg.moderation;
+    let templates = cfg.templates;
+    let schedule = cfg.schedule;
+    let announcements = cfg.announcements;
+    let welcome = cfg.welcome;
+    let direct = cfg.direct;
+    let invites = cfg.invites;
+    let store = match store::Store::load(cfg.store.file) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Can't load the state file.");
+            eprintln!();
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
 
     let strapi_integrations_endpoint = cfg
         .backend
@@ -132,7 +264,28 @@ async fn main() -> anyhow::Result<()> {
         admin_users,
         host,
         api_secret,
+        api_tokens,
+        moderation,
+        store,
+        templates,
+        schedule,
+        announcements,
+        welcome,
+        direct,
+        invites,
     };
 
-    matrix_bot(config).await
+    match &command[..] {
+        [] => matrix_bot(config).await,
+        [cmd, manifest] if cmd == "provision" => provision(config, manifest, false).await,
+        [cmd, manifest, flag] if cmd == "provision" && flag == "--dry-run" => {
+            provision(config, manifest, true).await
+        }
+        _ => {
+            eprintln!("Unknown command.");
+            eprintln!();
+            eprintln!("Usage: waasabi-matrix <path to config file> [provision <path to manifest> [--dry-run]]");
+            process::exit(1);
+        }
+    }
 }
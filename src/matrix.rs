@@ -1,35 +1,69 @@
 //! Matrix API calls.
 
+use crate::config::{self, RoomTemplate};
 use std::{
     collections::BTreeMap,
     convert::TryFrom,
     sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 
 use ruma::{
-    api::client::r0::{
-        alias::get_alias,
-        membership::invite_user::{self, InvitationRecipient},
-        message::send_message_event,
-        room::{create_room, Visibility},
-        state::{get_state_events_for_key, send_state_event},
+    api::{
+        client::{
+            error::ErrorKind,
+            r0::{
+                account::request_openid_token,
+                alias::get_alias,
+                capabilities::get_capabilities,
+                config::set_global_account_data,
+                membership::{
+                    ban_user, forget_room,
+                    get_member_events::{self, MembershipEventFilter},
+                    invite_user::{self, InvitationRecipient},
+                    join_room_by_id_or_alias, joined_members, kick_user, leave_room, unban_user,
+                    Invite3pidInit,
+                },
+                message::send_message_event,
+                redact::redact_event,
+                room::{
+                    create_room::{self, RoomPreset},
+                    report_content, upgrade_room, Visibility,
+                },
+                state::{get_state_events, get_state_events_for_key, send_state_event},
+            },
+            Error as ApiError,
+        },
+        error::{FromHttpResponseError, ServerError},
     },
     events::{
+        custom::CustomEventContent,
         room::{
+            avatar::AvatarEventContent,
             guest_access::{GuestAccess, GuestAccessEventContent},
             history_visibility::{HistoryVisibility, HistoryVisibilityEventContent},
             join_rules::{JoinRule, JoinRulesEventContent},
-            message::{MessageEventContent, MessageType, TextMessageEventContent},
+            member::MembershipState,
+            message::{
+                MessageEventContent, MessageType, NoticeMessageEventContent,
+                TextMessageEventContent,
+            },
+            name::NameEventContent,
             power_levels::PowerLevelsEventContent,
+            server_acl::ServerAclEventContent,
+            topic::TopicEventContent,
         },
         AnyInitialStateEvent, AnyMessageEventContent, AnyStateEventContent, EventType,
         InitialStateEvent,
     },
-    RoomAliasId, RoomId, UserId,
+    serde::Raw,
+    thirdparty::Medium,
+    EventId, Int, RoomAliasId, RoomId, RoomIdOrAliasId, RoomVersionId, UserId,
 };
-use ruma_client;
 type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
-use serde::Deserialize;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value, Map as JsonMap, Value as JsonValue};
 
 /// Monotonically increasing counter
 fn next_id() -> String {
@@ -45,33 +79,200 @@ pub async fn send_message<S: Into<String>>(
     room_id: &RoomId,
     msg: S,
 ) -> anyhow::Result<()> {
-    matrix_client
+    post_message(matrix_client, room_id, msg).await?;
+    Ok(())
+}
+
+/// Send a message to a room and return its event ID.
+///
+/// Sends the message as a unformatted plaintext message.
+pub async fn post_message<S: Into<String>>(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    msg: S,
+) -> anyhow::Result<EventId> {
+    let response = matrix_client
         .send_request(send_message_event::Request::new(
-            &room_id,
+            room_id,
             &next_id(),
             &AnyMessageEventContent::RoomMessage(MessageEventContent::new(MessageType::Text(
                 TextMessageEventContent::plain(msg),
             ))),
         ))
         .await?;
+    Ok(response.event_id)
+}
+
+/// React to an event with a key, usually an emoji.
+pub async fn send_reaction(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    key: &str,
+) -> anyhow::Result<()> {
+    // Reactions are not supported by ruma yet.
+    let content = json!({
+        "m.relates_to": {
+            "rel_type": "m.annotation",
+            "event_id": event_id,
+            "key": key,
+        }
+    });
+    let body = Raw::from_json(to_raw_value(&content)?);
+    let txn_id = next_id();
+    let req = send_message_event::Request::new_raw(room_id, &txn_id, "m.reaction", body);
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
+
+/// Send a notice to a room.
+///
+/// Notices are meant for automated messages, other bots don't react to them.
+pub async fn send_notice<S: Into<String>>(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    msg: S,
+) -> anyhow::Result<()> {
+    matrix_client
+        .send_request(send_message_event::Request::new(
+            room_id,
+            &next_id(),
+            &AnyMessageEventContent::RoomMessage(MessageEventContent::new(MessageType::Notice(
+                NoticeMessageEventContent::plain(msg),
+            ))),
+        ))
+        .await?;
+    Ok(())
+}
+
+/// Redact an event in a room.
+pub async fn redact_event(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    reason: Option<&str>,
+) -> anyhow::Result<()> {
+    let txn_id = next_id();
+    let mut req = redact_event::Request::new(room_id, event_id, &txn_id);
+    req.reason = reason;
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
+
+/// Report an event to the homeserver administrators.
+pub async fn report_content(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    reason: &str,
+) -> anyhow::Result<()> {
+    // -100 is the most offensive score.
+    let req = report_content::Request::new(room_id, event_id, Int::from(-100), reason);
+    matrix_client.send_request(req).await?;
     Ok(())
 }
 
+/// Create a `matrix.to` link to an event.
+pub fn event_link(room_id: &RoomId, event_id: &EventId) -> String {
+    format!("https://matrix.to/#/{}/{}", room_id, event_id)
+}
+
+/// Parse a `matrix.to` link to an event.
+///
+/// Returns the room ID or alias and the event ID.
+pub fn parse_event_link(link: &str) -> Option<(String, EventId)> {
+    let path = link.strip_prefix("https://matrix.to/#/")?;
+    let path = path.split('?').next()?;
+    let mut parts = path.split('/');
+    let room = percent_decode_str(parts.next()?).decode_utf8().ok()?;
+    let event = percent_decode_str(parts.next()?).decode_utf8().ok()?;
+    let event_id = EventId::try_from(&event[..]).ok()?;
+    Some((room.into_owned(), event_id))
+}
+
 /// Resolve a room alias to a room ID.
 ///
 /// Parses the room alias from a string.
 /// The room alias should be in the form `#roomname:homeserver`.
 pub async fn real_room_id(matrix_client: &Client, room_alias_id: &str) -> anyhow::Result<RoomId> {
+    find_room(matrix_client, room_alias_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("unknown room alias {}", room_alias_id))
+}
+
+/// Resolve a room alias to a room ID, if the alias exists.
+///
+/// Room IDs are returned as they are.
+pub async fn find_room(
+    matrix_client: &Client,
+    room_alias_id: &str,
+) -> anyhow::Result<Option<RoomId>> {
     if let Ok(room_id) = RoomId::try_from(room_alias_id) {
-        return Ok(room_id);
+        return Ok(Some(room_id));
     }
     let room_alias_id = RoomAliasId::try_from(room_alias_id)?;
 
-    let res = matrix_client
+    match matrix_client
         .send_request(get_alias::Request::new(&room_alias_id))
-        .await?;
-    let room_id = res.room_id;
-    Ok(room_id)
+        .await
+    {
+        Ok(res) => Ok(Some(res.room_id)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fetch the name of a room, if it has one.
+pub async fn room_name(matrix_client: &Client, room_id: &RoomId) -> anyhow::Result<Option<String>> {
+    let req = get_state_events_for_key::Request::new(room_id, EventType::RoomName, "");
+    match matrix_client.send_request(req).await {
+        Ok(resp) => {
+            let content: NameEventContent = resp.content.deserialize_as()?;
+            Ok(content.name().map(str::to_string))
+        }
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Set the name of a room.
+pub async fn set_room_name(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    name: &str,
+) -> anyhow::Result<()> {
+    let state_content = AnyStateEventContent::RoomName(NameEventContent::new(name.into())?);
+    let req = send_state_event::Request::new(room_id, "", &state_content);
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
+
+/// Fetch the topic of a room, if it has one.
+pub async fn room_topic(
+    matrix_client: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<Option<String>> {
+    let req = get_state_events_for_key::Request::new(room_id, EventType::RoomTopic, "");
+    match matrix_client.send_request(req).await {
+        Ok(resp) => {
+            let content: TopicEventContent = resp.content.deserialize_as()?;
+            Ok(Some(content.topic))
+        }
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Set the topic of a room.
+pub async fn set_room_topic(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    topic: &str,
+) -> anyhow::Result<()> {
+    let state_content = AnyStateEventContent::RoomTopic(TopicEventContent::new(topic.into()));
+    let req = send_state_event::Request::new(room_id, "", &state_content);
+    matrix_client.send_request(req).await?;
+    Ok(())
 }
 
 /// Invite a user to a room.
@@ -86,130 +287,742 @@ pub async fn invite_user(
     let user_id = UserId::try_from(user_id)?;
     let recipient = InvitationRecipient::UserId { user_id: &user_id };
     matrix_client
-        .send_request(invite_user::Request::new(&room_id, recipient))
+        .send_request(invite_user::Request::new(room_id, recipient))
+        .await?;
+
+    Ok(())
+}
+
+/// Invite someone to a room by their email address.
+///
+/// The identity server stores the invite until the email address is bound to a Matrix account.
+pub async fn invite_email(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    id_server: &str,
+    id_access_token: &str,
+    email: &str,
+) -> anyhow::Result<()> {
+    let invite = Invite3pidInit {
+        id_server,
+        id_access_token,
+        medium: Medium::Email,
+        address: email,
+    };
+    let recipient = InvitationRecipient::ThirdPartyId(invite.into());
+    matrix_client
+        .send_request(invite_user::Request::new(room_id, recipient))
+        .await?;
+
+    Ok(())
+}
+
+/// Request an OpenID token for the bot, to prove its identity to a third party.
+pub async fn request_openid_token(
+    matrix_client: &Client,
+    bot_id: &UserId,
+) -> anyhow::Result<request_openid_token::Response> {
+    let res = matrix_client
+        .send_request(request_openid_token::Request::new(bot_id))
+        .await?;
+    Ok(res)
+}
+
+/// Leave a room.
+pub async fn leave_room(matrix_client: &Client, room_id: &RoomId) -> anyhow::Result<()> {
+    matrix_client
+        .send_request(leave_room::Request::new(room_id))
+        .await?;
+    Ok(())
+}
+
+/// Forget a room the bot left, so it no longer shows up in syncs.
+pub async fn forget_room(matrix_client: &Client, room_id: &RoomId) -> anyhow::Result<()> {
+    matrix_client
+        .send_request(forget_room::Request::new(room_id))
+        .await?;
+    Ok(())
+}
+
+/// Join a room by its ID or alias.
+pub async fn join_room(matrix_client: &Client, room: &str) -> anyhow::Result<RoomId> {
+    let room = RoomIdOrAliasId::try_from(room)?;
+    let res = matrix_client
+        .send_request(join_room_by_id_or_alias::Request::new(&room))
+        .await?;
+    Ok(res.room_id)
+}
+
+/// List all users currently joined to a room.
+pub async fn joined_members(
+    matrix_client: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<Vec<UserId>> {
+    let res = matrix_client
+        .send_request(joined_members::Request::new(room_id))
+        .await?;
+    Ok(res.joined.into_keys().collect())
+}
+
+/// A user's membership in a room.
+#[derive(Deserialize)]
+pub struct Membership {
+    /// The membership state.
+    pub membership: MembershipState,
+    /// The reason given for the last membership change.
+    pub reason: Option<String>,
+}
+
+/// A member event in a room's state.
+#[derive(Deserialize)]
+struct MemberState {
+    /// The member's user ID.
+    state_key: UserId,
+    content: Membership,
+}
+
+/// List the users banned from a room, with the reason given for each ban.
+pub async fn banned_users(
+    matrix_client: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<Vec<(UserId, Option<String>)>> {
+    let mut req = get_member_events::Request::new(room_id);
+    req.membership = Some(MembershipEventFilter::Ban);
+    let resp = matrix_client.send_request(req).await?;
+
+    let banned = resp
+        .chunk
+        .iter()
+        .filter_map(|event| event.deserialize_as::<MemberState>().ok())
+        .filter(|member| member.content.membership == MembershipState::Ban)
+        .map(|member| (member.state_key, member.content.reason))
+        .collect();
+    Ok(banned)
+}
+
+/// Fetch the membership state of a user in a room.
+///
+/// Returns `None` if the user was never in the room.
+pub async fn membership(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> anyhow::Result<Option<MembershipState>> {
+    let req =
+        get_state_events_for_key::Request::new(room_id, EventType::RoomMember, user_id.as_str());
+    match matrix_client.send_request(req).await {
+        Ok(resp) => Ok(Some(
+            resp.content.deserialize_as::<Membership>()?.membership,
+        )),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Ban a user from a room.
+pub async fn ban_user(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+    reason: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut req = ban_user::Request::new(room_id, user_id);
+    req.reason = reason;
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
+
+/// Lift a user's ban from a room.
+pub async fn unban_user(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> anyhow::Result<()> {
+    matrix_client
+        .send_request(unban_user::Request::new(room_id, user_id))
         .await?;
+    Ok(())
+}
+
+/// Kick a user from a room.
+pub async fn kick_user(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+    reason: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut req = kick_user::Request::new(room_id, user_id);
+    req.reason = reason;
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
+
+/// Fetch the current power levels of a room.
+pub async fn power_levels(
+    matrix_client: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<PowerLevelsEventContent> {
+    let req = get_state_events_for_key::Request::new(room_id, EventType::RoomPowerLevels, "");
+    let resp = matrix_client.send_request(req).await?;
+    let content = resp.content.deserialize_as()?;
+    Ok(content)
+}
+
+/// Check whether a request failed because the requested resource does not exist.
+fn is_not_found<E>(err: &ruma_client::Error<E, ApiError>) -> bool {
+    matches!(
+        err,
+        ruma_client::Error::FromHttpResponse(FromHttpResponseError::Http(ServerError::Known(e)))
+            if e.status_code == http::StatusCode::NOT_FOUND
+    )
+}
+
+/// How long to wait before retrying a request the homeserver rejected as rate-limited.
+///
+/// Returns `None` if the request failed for another reason.
+pub fn rate_limited(err: &anyhow::Error) -> Option<Duration> {
+    match err.downcast_ref::<ruma_client::Error<hyper::Error, ApiError>>()? {
+        ruma_client::Error::FromHttpResponse(FromHttpResponseError::Http(ServerError::Known(
+            ApiError {
+                kind: ErrorKind::LimitExceeded { retry_after_ms },
+                ..
+            },
+        ))) => Some(retry_after_ms.unwrap_or_else(|| Duration::from_secs(1))),
+        _ => None,
+    }
+}
+
+/// Whether a request failed because an access token was rejected.
+pub fn unauthorized(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ruma_client::Error<hyper::Error, ApiError>>(),
+        Some(ruma_client::Error::FromHttpResponse(FromHttpResponseError::Http(ServerError::Known(e))))
+            if matches!(e.kind, ErrorKind::UnknownToken { .. })
+                || e.status_code == http::StatusCode::UNAUTHORIZED
+    )
+}
+
+/// Fetch the current server ACL of a room.
+///
+/// Returns `None` if the room has no server ACL.
+pub async fn server_acl(
+    matrix_client: &Client,
+    room_id: &RoomId,
+) -> anyhow::Result<Option<ServerAclEventContent>> {
+    let req = get_state_events_for_key::Request::new(room_id, EventType::RoomServerAcl, "");
+    match matrix_client.send_request(req).await {
+        Ok(resp) => Ok(Some(resp.content.deserialize_as()?)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replace the server ACL of a room.
+pub async fn set_server_acl(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    acl: ServerAclEventContent,
+) -> anyhow::Result<()> {
+    let state_content = AnyStateEventContent::RoomServerAcl(acl);
+    let req = send_state_event::Request::new(room_id, "", &state_content);
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
+
+/// Levels at the top of the power levels event that can be changed.
+const LEVEL_FIELDS: &[&str] = &[
+    "ban",
+    "events_default",
+    "invite",
+    "kick",
+    "redact",
+    "state_default",
+    "users_default",
+];
+
+/// A change to the power levels of a room.
+///
+/// Everything not mentioned is left as it is.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PowerLevelChange {
+    /// Users to set to a level.
+    #[serde(default)]
+    pub users: BTreeMap<UserId, Int>,
+    /// Users to remove, so they fall back to `users_default`.
+    #[serde(default)]
+    pub remove_users: Vec<UserId>,
+    /// Event types to set a required level for.
+    #[serde(default)]
+    pub events: BTreeMap<String, Int>,
+    /// Event types to remove, so they fall back to `events_default` or `state_default`.
+    #[serde(default)]
+    pub remove_events: Vec<String>,
+    /// Levels at the top of the event: `users_default`, `events_default`, `state_default`,
+    /// `ban`, `kick`, `redact` and `invite`.
+    #[serde(default)]
+    pub levels: BTreeMap<String, Int>,
+    /// Levels required to trigger notifications, like `room`.
+    #[serde(default)]
+    pub notifications: BTreeMap<String, Int>,
+}
+
+impl PowerLevelChange {
+    /// Set all the given users to the same level.
+    pub fn set_users(user_ids: &[UserId], level: Int) -> Self {
+        PowerLevelChange {
+            users: user_ids.iter().map(|u| (u.clone(), level)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Apply the change to the raw content of a power levels event.
+    fn apply(&self, content: &mut JsonMap<String, JsonValue>) -> anyhow::Result<()> {
+        if let Some(field) = self
+            .levels
+            .keys()
+            .find(|field| !LEVEL_FIELDS.contains(&&field[..]))
+        {
+            anyhow::bail!("unknown power level field {}", field);
+        }
+        for (field, level) in &self.levels {
+            content.insert(field.clone(), json!(level));
+        }
+
+        let users = object_field(content, "users")?;
+        for user_id in &self.remove_users {
+            users.remove(user_id.as_str());
+        }
+        for (user_id, level) in &self.users {
+            users.insert(user_id.to_string(), json!(level));
+        }
+
+        let events = object_field(content, "events")?;
+        for event_type in &self.remove_events {
+            events.remove(event_type);
+        }
+        for (event_type, level) in &self.events {
+            events.insert(event_type.clone(), json!(level));
+        }
+
+        let notifications = object_field(content, "notifications")?;
+        for (kind, level) in &self.notifications {
+            notifications.insert(kind.clone(), json!(level));
+        }
+
+        Ok(())
+    }
+}
+
+/// Get a field holding an object, creating it if it's missing.
+fn object_field<'a>(
+    content: &'a mut JsonMap<String, JsonValue>,
+    field: &str,
+) -> anyhow::Result<&'a mut JsonMap<String, JsonValue>> {
+    content
+        .entry(field)
+        .or_insert_with(|| JsonValue::Object(JsonMap::new()))
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("power levels field {} is not an object", field))
+}
+
+/// Change the power levels of a room.
+///
+/// The current event is read and written back with only the changed levels replaced,
+/// keeping every other field, including ones unknown to the bot.
+pub async fn update_power_levels(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    change: &PowerLevelChange,
+) -> anyhow::Result<()> {
+    let req = get_state_events_for_key::Request::new(room_id, EventType::RoomPowerLevels, "");
+    let resp = matrix_client.send_request(req).await?;
+    let mut content: JsonMap<String, JsonValue> = resp.content.deserialize_as()?;
+
+    change.apply(&mut content)?;
+
+    let body = Raw::from_json(to_raw_value(&content)?);
+    let event_type = EventType::RoomPowerLevels.to_string();
+    let req = send_state_event::Request::new_raw(room_id, &event_type, "", body);
+    matrix_client.send_request(req).await?;
 
     Ok(())
 }
 
+/// Mute a user in a room.
+///
+/// Lowers the user's power level below the level required to send messages, never raises it.
+/// For a locked room, `locked_default` is its `events_default` from before it was locked,
+/// so the user stays muted once it is unlocked.
+///
+/// Returns the user's previously set power level, if any, to be restored by [`unmute_user`].
+pub async fn mute_user(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+    locked_default: Option<Int>,
+) -> anyhow::Result<Option<Int>> {
+    let content = power_levels(matrix_client, room_id).await?;
+
+    let message_level = content
+        .events
+        .get(&EventType::RoomMessage)
+        .copied()
+        .unwrap_or_else(|| locked_default.unwrap_or(content.events_default));
+    let previous = content.users.get(user_id).copied();
+    let level = previous
+        .unwrap_or(content.users_default)
+        .min(message_level - Int::from(1));
+
+    let change = PowerLevelChange::set_users(std::slice::from_ref(user_id), level);
+    update_power_levels(matrix_client, room_id, &change).await?;
+
+    Ok(previous)
+}
+
+/// Unmute a previously muted user in a room.
+///
+/// Restores the user's previous power level or resets it to the room's default.
+pub async fn unmute_user(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+    previous: Option<Int>,
+) -> anyhow::Result<()> {
+    let change = match previous {
+        Some(level) => PowerLevelChange::set_users(std::slice::from_ref(user_id), level),
+        None => PowerLevelChange {
+            remove_users: vec![user_id.clone()],
+            ..Default::default()
+        },
+    };
+    update_power_levels(matrix_client, room_id, &change).await
+}
+
+/// Set the default power level required to send events in a room.
+///
+/// Returns the previous default level.
+pub async fn set_events_default(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    level: Int,
+) -> anyhow::Result<Int> {
+    let previous = power_levels(matrix_client, room_id).await?.events_default;
+
+    let mut change = PowerLevelChange::default();
+    change.levels.insert("events_default".into(), level);
+    update_power_levels(matrix_client, room_id, &change).await?;
+
+    Ok(previous)
+}
+
 /// Create a new room.
+///
+/// The room is set up according to the template.
+/// A given topic takes precedence over the template's topic.
+/// With `space` set, the room is created as a space.
 pub async fn create_room(
     matrix_client: &Client,
     alias: &str,
     name: &str,
     topic: Option<&str>,
     invite: &[UserId],
+    template: &RoomTemplate,
+    space: bool,
 ) -> anyhow::Result<RoomId> {
     use AnyInitialStateEvent::*;
 
     let mut req = create_room::Request::new();
     req.room_alias_name = Some(alias);
     req.name = Some(name);
-    req.topic = topic;
-    req.visibility = Visibility::Private;
+    req.topic = topic.or(template.topic.as_deref());
+    req.visibility = match template.visibility {
+        Some(config::RoomVisibility::Public) => Visibility::Public,
+        Some(config::RoomVisibility::Private) | None => Visibility::Private,
+    };
     req.invite = invite;
+    req.room_version = template.room_version.as_ref();
+    if let Some(levels) = &template.power_levels {
+        req.power_level_content_override = Some(Raw::from_json(to_raw_value(levels)?));
+    }
+
+    let guest_access = match template.guest_access {
+        Some(config::GuestAccess::CanJoin) | None => GuestAccess::CanJoin,
+        Some(config::GuestAccess::Forbidden) => GuestAccess::Forbidden,
+    };
+    let join_rule = match template.join_rules {
+        Some(config::JoinRules::Public) => JoinRule::Public,
+        Some(config::JoinRules::Invite) | None => JoinRule::Invite,
+    };
+    let history_visibility = match template.history_visibility {
+        Some(config::HistoryVisibility::Invited) => HistoryVisibility::Invited,
+        Some(config::HistoryVisibility::Joined) => HistoryVisibility::Joined,
+        Some(config::HistoryVisibility::Shared) | None => HistoryVisibility::Shared,
+        Some(config::HistoryVisibility::WorldReadable) => HistoryVisibility::WorldReadable,
+    };
 
-    let initial_state = &[
+    let mut initial_state = vec![
         RoomGuestAccess(InitialStateEvent {
-            content: GuestAccessEventContent::new(GuestAccess::CanJoin),
+            content: GuestAccessEventContent::new(guest_access),
             state_key: "".into(),
         }),
         RoomJoinRules(InitialStateEvent {
-            content: JoinRulesEventContent::new(JoinRule::Invite),
+            content: JoinRulesEventContent::new(join_rule),
             state_key: "".into(),
         }),
         RoomHistoryVisibility(InitialStateEvent {
-            content: HistoryVisibilityEventContent::new(HistoryVisibility::Shared),
+            content: HistoryVisibilityEventContent::new(history_visibility),
             state_key: "".into(),
         }),
     ];
-    req.initial_state = initial_state;
+    if let Some(avatar) = &template.avatar {
+        initial_state.push(RoomAvatar(InitialStateEvent {
+            content: AvatarEventContent::new(avatar.clone()),
+            state_key: "".into(),
+        }));
+    }
+    req.initial_state = &initial_state;
 
-    let response = matrix_client.send_request(req).await?;
+    // The room type is not supported by ruma yet, so it is added to the request body directly.
+    let response = matrix_client
+        .send_customized_request(req, |http_req| {
+            if space {
+                let body = http_req.body_mut();
+                let mut content: JsonMap<String, JsonValue> = serde_json::from_slice(body)
+                    .map_err(|e| ruma_client::Error::IntoHttp(e.into()))?;
+                content.insert("creation_content".into(), json!({ "type": "m.space" }));
+                let content = serde_json::to_vec(&content)
+                    .map_err(|e| ruma_client::Error::IntoHttp(e.into()))?;
+                body.clear();
+                body.extend_from_slice(&content);
+            }
+            Ok(())
+        })
+        .await?;
     let room_id = response.room_id;
 
     Ok(room_id)
 }
 
-#[derive(Deserialize)]
-struct PowerLevelEvents {
-    events: BTreeMap<String, u32>,
-    users: BTreeMap<String, u32>,
+/// A state event of any type, with its content as plain JSON.
+#[derive(Deserialize, Debug)]
+pub struct StateEntry {
+    /// The event type.
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// The state key.
+    pub state_key: String,
+    /// The event content.
+    pub content: JsonValue,
 }
 
-/// Give a user admin capabilities in a room.
-pub async fn op_user(
+/// Fetch the complete current state of a room.
+pub async fn room_state(
     matrix_client: &Client,
     room_id: &RoomId,
-    user_ids: &[UserId],
+) -> anyhow::Result<Vec<StateEntry>> {
+    let resp = matrix_client
+        .send_request(get_state_events::Request::new(room_id))
+        .await?;
+    let state = resp
+        .room_state
+        .iter()
+        .map(|event| event.deserialize_as())
+        .collect::<Result<_, _>>()?;
+    Ok(state)
+}
+
+/// Fetch the content of a state event of any type as plain JSON.
+///
+/// Returns `None` if the room has no such event.
+pub async fn raw_state(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+) -> anyhow::Result<Option<JsonValue>> {
+    let req = get_state_events_for_key::Request::new(room_id, event_type.into(), state_key);
+    match matrix_client.send_request(req).await {
+        Ok(resp) => Ok(Some(resp.content.deserialize_as()?)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Send a state event of any type with plain JSON content.
+///
+/// The content is sent as is, keeping fields unknown to the bot.
+pub async fn send_raw_state(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+    content: &JsonValue,
 ) -> anyhow::Result<()> {
-    // Get the current power levels.
-    let req = get_state_events_for_key::Request::new(room_id, EventType::RoomPowerLevels, "");
-    let resp = matrix_client.send_request(req).await?;
+    let body = Raw::from_json(to_raw_value(content)?);
+    let req = send_state_event::Request::new_raw(room_id, event_type, state_key, body);
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
 
-    let content: PowerLevelEvents = resp.content.deserialize_as()?;
+/// The room versions the homeserver supports: the default version and all available versions.
+pub async fn room_versions(
+    matrix_client: &Client,
+) -> anyhow::Result<(RoomVersionId, Vec<RoomVersionId>)> {
+    let resp = matrix_client
+        .send_request(get_capabilities::Request::new())
+        .await?;
+    let versions = resp.capabilities.room_versions;
+    Ok((versions.default, versions.available.into_keys().collect()))
+}
 
-    let mut user_map = BTreeMap::new();
+/// Upgrade a room to a new room version.
+///
+/// Returns the ID of the room replacing it.
+pub async fn upgrade_room(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    version: &RoomVersionId,
+) -> anyhow::Result<RoomId> {
+    let resp = matrix_client
+        .send_request(upgrade_room::Request::new(room_id, version))
+        .await?;
+    Ok(resp.replacement_room)
+}
 
-    // Set old state
-    for (user, level) in content.users {
-        let user_id = match UserId::try_from(user) {
-            Ok(id) => id,
-            Err(_) => continue,
-        };
-        user_map.insert(user_id, level.into());
-    }
+/// Send a state event of a type unknown to ruma.
+async fn send_custom_state(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+    content: JsonValue,
+) -> anyhow::Result<()> {
+    let data = match content {
+        JsonValue::Object(data) => data.into_iter().collect(),
+        _ => anyhow::bail!("state event content must be an object"),
+    };
+    let state_content = AnyStateEventContent::Custom(CustomEventContent {
+        event_type: event_type.into(),
+        data,
+    });
+    let req = send_state_event::Request::new(room_id, state_key, &state_content);
+    matrix_client.send_request(req).await?;
+    Ok(())
+}
 
-    // Now add the new users
-    for user_id in user_ids.iter() {
-        user_map.insert(user_id.clone(), 100.into());
+/// Add a room to a space.
+///
+/// Sets the `m.space.child` event in the space and the `m.space.parent` event in the room.
+/// Children of a space are sorted by their `order`.
+pub async fn add_to_space(
+    matrix_client: &Client,
+    space_id: &RoomId,
+    room_id: &RoomId,
+    order: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut child = json!({ "via": [room_id.server_name().as_str()] });
+    if let Some(order) = order {
+        child["order"] = json!(order);
     }
+    send_custom_state(
+        matrix_client,
+        space_id,
+        "m.space.child",
+        room_id.as_str(),
+        child,
+    )
+    .await?;
 
-    let mut event_map = BTreeMap::new();
-
-    // default state
-    event_map.insert(EventType::RoomAvatar, 50.into());
-    event_map.insert(EventType::RoomCanonicalAlias, 50.into());
-    event_map.insert(EventType::RoomEncrypted, 100.into());
-    event_map.insert(EventType::RoomHistoryVisibility, 100.into());
-    event_map.insert(EventType::RoomName, 50.into());
-    event_map.insert(EventType::RoomPowerLevels, 100.into());
-    event_map.insert(EventType::RoomServerAcl, 100.into());
-    event_map.insert(EventType::RoomTombstone, 100.into());
-
-    // overwriting with old state
-    if let Some(&level) = content.events.get("m.room.avatar") {
-        event_map.insert(EventType::RoomAvatar, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.canonical_alias") {
-        event_map.insert(EventType::RoomCanonicalAlias, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.encrypted") {
-        event_map.insert(EventType::RoomEncrypted, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.history_visibility") {
-        event_map.insert(EventType::RoomHistoryVisibility, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.name") {
-        event_map.insert(EventType::RoomName, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.power_levels") {
-        event_map.insert(EventType::RoomPowerLevels, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.server_acl") {
-        event_map.insert(EventType::RoomServerAcl, level.into());
-    }
-    if let Some(&level) = content.events.get("m.room.tombstone") {
-        event_map.insert(EventType::RoomTombstone, level.into());
+    let parent = json!({ "via": [space_id.server_name().as_str()], "canonical": true });
+    send_custom_state(
+        matrix_client,
+        room_id,
+        "m.space.parent",
+        space_id.as_str(),
+        parent,
+    )
+    .await
+}
+
+/// Check whether a room is listed as a child of a space.
+pub async fn is_space_child(
+    matrix_client: &Client,
+    space_id: &RoomId,
+    room_id: &RoomId,
+) -> anyhow::Result<bool> {
+    let event_type = EventType::from("m.space.child");
+    let req = get_state_events_for_key::Request::new(space_id, event_type, room_id.as_str());
+    match matrix_client.send_request(req).await {
+        Ok(resp) => {
+            let content: JsonValue = resp.content.deserialize_as()?;
+            Ok(content["via"].as_array().is_some_and(|via| !via.is_empty()))
+        }
+        Err(e) if is_not_found(&e) => Ok(false),
+        Err(e) => Err(e.into()),
     }
+}
+
+/// Remove a room from a space.
+///
+/// Empties the `m.space.child` event in the space and the `m.space.parent` event in the room.
+pub async fn remove_from_space(
+    matrix_client: &Client,
+    space_id: &RoomId,
+    room_id: &RoomId,
+) -> anyhow::Result<()> {
+    send_custom_state(
+        matrix_client,
+        space_id,
+        "m.space.child",
+        room_id.as_str(),
+        json!({}),
+    )
+    .await?;
+    send_custom_state(
+        matrix_client,
+        room_id,
+        "m.space.parent",
+        space_id.as_str(),
+        json!({}),
+    )
+    .await
+}
 
-    let mut content = PowerLevelsEventContent::new();
-    content.events = event_map;
-    content.users = user_map;
-    let state_content = AnyStateEventContent::RoomPowerLevels(content);
+/// Create a direct message room with a user.
+pub async fn create_direct_room(
+    matrix_client: &Client,
+    user_id: &UserId,
+) -> anyhow::Result<RoomId> {
+    let invite = [user_id.clone()];
+    let mut req = create_room::Request::new();
+    req.is_direct = true;
+    req.preset = Some(RoomPreset::PrivateChat);
+    req.visibility = Visibility::Private;
+    req.invite = &invite;
 
-    let req = send_state_event::Request::new(room_id, "", &state_content);
-    matrix_client.send_request(req).await?;
+    let response = matrix_client.send_request(req).await?;
+    Ok(response.room_id)
+}
 
+/// Replace the bot's list of direct message rooms (`m.direct`), by the user they are with.
+pub async fn set_direct_rooms(
+    matrix_client: &Client,
+    bot_id: &UserId,
+    rooms: &BTreeMap<UserId, Vec<RoomId>>,
+) -> anyhow::Result<()> {
+    let data = to_raw_value(rooms)?;
+    let event_type = EventType::Direct.to_string();
+    let req = set_global_account_data::Request::new(&data, &event_type, bot_id);
+    matrix_client.send_request(req).await?;
     Ok(())
 }
+
+/// Give users admin capabilities in a room.
+pub async fn op_user(
+    matrix_client: &Client,
+    room_id: &RoomId,
+    user_ids: &[UserId],
+) -> anyhow::Result<()> {
+    let change = PowerLevelChange::set_users(user_ids, Int::from(100));
+    update_power_levels(matrix_client, room_id, &change).await
+}
@@ -0,0 +1,505 @@
+//! Moderation actions spanning multiple rooms.
+//!
+//! Trolls tend to hop between channels,
+//! so actions against a user can be applied to every room the bot manages at once.
+
+use crate::{
+    audit::{AuditLog, Record},
+    matrix,
+    store::Store,
+};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use ruma::{
+    events::{
+        room::{power_levels::PowerLevelsEventContent, server_acl::ServerAclEventContent},
+        EventType,
+    },
+    Int, RoomId, ServerName, UserId,
+};
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+use serde::{Deserialize, Serialize};
+
+/// A moderation action to apply to a user.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Ban the user from the room.
+    Ban,
+    /// Kick the user out of the room.
+    Kick,
+    /// Take away the user's permission to send messages.
+    Mute,
+}
+
+impl Action {
+    /// The name of the action.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Ban => "ban",
+            Action::Kick => "kick",
+            Action::Mute => "mute",
+        }
+    }
+
+    /// A past-tense verb describing the action, for use in summaries.
+    pub fn past_tense(&self) -> &'static str {
+        match self {
+            Action::Ban => "Banned",
+            Action::Kick => "Kicked",
+            Action::Mute => "Muted",
+        }
+    }
+}
+
+/// The outcome of a moderation action in a single room.
+#[derive(Debug, Serialize)]
+pub struct RoomResult {
+    /// The room the action was applied in.
+    pub room_id: RoomId,
+    /// The reason the action failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Apply a moderation action against a user in each of the given rooms.
+///
+/// Rooms in which the bot does not have enough power are skipped and reported as failed.
+/// Every successful action is recorded in the audit log on behalf of the actor.
+/// Returns the outcome for every room.
+#[allow(clippy::too_many_arguments)]
+pub async fn everywhere(
+    client: &Client,
+    audit: &AuditLog,
+    store: &Store,
+    actor: &str,
+    bot_id: &UserId,
+    rooms: &[RoomId],
+    user_id: &UserId,
+    action: Action,
+    reason: Option<&str>,
+) -> Vec<RoomResult> {
+    let mut results = Vec::with_capacity(rooms.len());
+    for room_id in rooms {
+        let error = match apply(client, store, bot_id, room_id, user_id, action, reason).await {
+            Ok(()) => {
+                let record = Record::new(actor, action.name())
+                    .target(user_id)
+                    .room(room_id)
+                    .reason(reason);
+                audit.record(record).await;
+                None
+            }
+            Err(e) => {
+                log::warn!(
+                    "(Room: {}) Failed to apply {:?} against {}. Error: {:?}",
+                    room_id,
+                    action,
+                    user_id,
+                    e
+                );
+                Some(e.to_string())
+            }
+        };
+        results.push(RoomResult {
+            room_id: room_id.clone(),
+            error,
+        });
+    }
+    results
+}
+
+/// Apply a moderation action against a user in a single room.
+async fn apply(
+    client: &Client,
+    store: &Store,
+    bot_id: &UserId,
+    room_id: &RoomId,
+    user_id: &UserId,
+    action: Action,
+    reason: Option<&str>,
+) -> anyhow::Result<()> {
+    let levels = matrix::power_levels(client, room_id).await?;
+    check_power(&levels, bot_id, user_id, action)?;
+
+    match action {
+        Action::Ban => matrix::ban_user(client, room_id, user_id, reason).await,
+        Action::Kick => matrix::kick_user(client, room_id, user_id, reason).await,
+        Action::Mute => matrix::mute_user(client, room_id, user_id, locked_default(store, room_id))
+            .await
+            .map(|_| ()),
+    }
+}
+
+/// Get the power level of a user.
+pub fn user_level(levels: &PowerLevelsEventContent, user_id: &UserId) -> Int {
+    levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(levels.users_default)
+}
+
+/// Check that the bot has enough power to apply the action against the user.
+fn check_power(
+    levels: &PowerLevelsEventContent,
+    bot_id: &UserId,
+    user_id: &UserId,
+    action: Action,
+) -> anyhow::Result<()> {
+    let bot_level = user_level(levels, bot_id);
+    let required = match action {
+        Action::Ban => levels.ban,
+        Action::Kick => levels.kick,
+        Action::Mute => levels
+            .events
+            .get(&EventType::RoomPowerLevels)
+            .copied()
+            .unwrap_or(levels.state_default),
+    };
+
+    if bot_level < required {
+        bail!(
+            "insufficient power level (have {}, need {})",
+            bot_level,
+            required
+        );
+    }
+    if bot_level <= user_level(levels, user_id) {
+        bail!("user has an equal or higher power level");
+    }
+
+    Ok(())
+}
+
+/// The `events_default` power level of a room from before it was locked.
+///
+/// Returns `None` if the room is not locked.
+fn locked_default(store: &Store, room_id: &RoomId) -> Option<Int> {
+    store.read(|data| data.locked.get(room_id).copied())
+}
+
+/// The power level required to send messages in a locked room.
+const LOCKED_LEVEL: i32 = 50;
+
+/// Make a room read-only for everyone below moderator level.
+///
+/// Raises the room's `events_default` power level and remembers the previous value.
+///
+/// Returns `false` if the room was already locked.
+pub async fn lock(
+    client: &Client,
+    audit: &AuditLog,
+    store: &Store,
+    actor: &str,
+    room_id: &RoomId,
+) -> anyhow::Result<bool> {
+    if store.read(|data| data.locked.contains_key(room_id)) {
+        return Ok(false);
+    }
+
+    let levels = matrix::power_levels(client, room_id).await?;
+    let level = levels.events_default.max(Int::from(LOCKED_LEVEL));
+    let previous = matrix::set_events_default(client, room_id, level).await?;
+    store.update(|data| data.locked.insert(room_id.clone(), previous))?;
+
+    audit.record(Record::new(actor, "lock").room(room_id)).await;
+    Ok(true)
+}
+
+/// Restore the `events_default` power level of a locked room.
+///
+/// Returns `false` if the room was not locked.
+pub async fn unlock(
+    client: &Client,
+    audit: &AuditLog,
+    store: &Store,
+    actor: &str,
+    room_id: &RoomId,
+) -> anyhow::Result<bool> {
+    let previous = match store.read(|data| data.locked.get(room_id).copied()) {
+        Some(previous) => previous,
+        None => return Ok(false),
+    };
+
+    matrix::set_events_default(client, room_id, previous).await?;
+    store.update(|data| data.locked.remove(room_id))?;
+
+    audit
+        .record(Record::new(actor, "unlock").room(room_id))
+        .await;
+    Ok(true)
+}
+
+/// How often to check for temporary mutes that ended.
+const UNMUTE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A temporary mute of a user in a room.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mute {
+    /// The room the user is muted in.
+    pub room_id: RoomId,
+    /// The muted user.
+    pub user_id: UserId,
+    /// The user's power level from before the mute, if it was set explicitly.
+    pub previous: Option<Int>,
+    /// When the mute ends.
+    pub until: DateTime<Utc>,
+}
+
+/// Mute a user in a room for the given duration.
+///
+/// The mute is kept in the state store and lifted by [`unmute_expired`], even after a restart.
+/// Muting a user who is still muted extends the mute and keeps their level from before the first one.
+pub async fn mute_for(
+    client: &Client,
+    store: &Store,
+    room_id: &RoomId,
+    user_id: &UserId,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let until = chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| Utc::now().checked_add_signed(duration))
+        .ok_or_else(|| anyhow!("mute duration out of range"))?;
+    let previous =
+        matrix::mute_user(client, room_id, user_id, locked_default(store, room_id)).await?;
+
+    store.update(|data| {
+        let muted = data
+            .mutes
+            .iter_mut()
+            .find(|mute| mute.room_id == *room_id && mute.user_id == *user_id);
+        match muted {
+            Some(mute) => mute.until = mute.until.max(until),
+            None => data.mutes.push(Mute {
+                room_id: room_id.clone(),
+                user_id: user_id.clone(),
+                previous,
+                until,
+            }),
+        }
+    })?;
+    Ok(())
+}
+
+/// Lift temporary mutes once they end.
+///
+/// Mutes that ended while the bot was not running are lifted right away.
+pub async fn unmute_expired(client: Client, store: Store) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(UNMUTE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now();
+        let expired = store.read(|data| {
+            data.mutes
+                .iter()
+                .filter(|mute| mute.until <= now)
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+
+        for mute in expired {
+            let unmuted =
+                matrix::unmute_user(&client, &mute.room_id, &mute.user_id, mute.previous).await;
+            if let Err(e) = unmuted {
+                log::error!(
+                    "(Room: {}) Failed to unmute {}. Error: {:?}",
+                    mute.room_id,
+                    mute.user_id,
+                    e
+                );
+            }
+
+            // The mute might have been extended in the meantime.
+            let removed = store.update(|data| {
+                data.mutes.retain(|m| {
+                    m.room_id != mute.room_id || m.user_id != mute.user_id || m.until > now
+                })
+            });
+            if let Err(e) = removed {
+                log::error!(
+                    "Failed to remove the mute of {}. Error: {:?}",
+                    mute.user_id,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// A change to the server ACL of rooms.
+#[derive(Deserialize, Debug, Default)]
+pub struct AclChange {
+    /// Server name globs to add to the deny list.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Server name globs to add to the allow list.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Server name globs to remove from both lists.
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+impl AclChange {
+    /// Check whether the change does nothing.
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.allow.is_empty() && self.remove.is_empty()
+    }
+
+    /// Apply the change to a server ACL.
+    fn apply(&self, acl: &mut ServerAclEventContent) {
+        for server in &self.remove {
+            acl.allow.retain(|s| s != server);
+            acl.deny.retain(|s| s != server);
+        }
+        for server in &self.deny {
+            acl.allow.retain(|s| s != server);
+            if !acl.deny.contains(server) {
+                acl.deny.push(server.clone());
+            }
+        }
+        for server in &self.allow {
+            acl.deny.retain(|s| s != server);
+            if !acl.allow.contains(server) {
+                acl.allow.push(server.clone());
+            }
+        }
+    }
+}
+
+/// The server ACL of a single room after a change.
+#[derive(Debug, Serialize)]
+pub struct AclResult {
+    /// The room the change was applied in.
+    pub room_id: RoomId,
+    /// The allowed servers.
+    pub allow: Vec<String>,
+    /// The denied servers.
+    pub deny: Vec<String>,
+    /// Whether the change modified the ACL.
+    pub changed: bool,
+    /// The reason the change failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Apply a change to the server ACL of each of the given rooms.
+///
+/// With `dry_run` nothing is changed, the result only shows what the ACLs would look like.
+/// Changes that would lock out the bot's own server are refused.
+/// Every modified ACL is recorded in the audit log on behalf of the actor.
+/// Returns the resulting ACL for every room.
+#[allow(clippy::too_many_arguments)]
+pub async fn acl_everywhere(
+    client: &Client,
+    audit: &AuditLog,
+    actor: &str,
+    bot_id: &UserId,
+    rooms: &[RoomId],
+    change: &AclChange,
+    dry_run: bool,
+) -> Vec<AclResult> {
+    let mut results = Vec::with_capacity(rooms.len());
+    for room_id in rooms {
+        let result = match update_acl(client, bot_id.server_name(), room_id, change, dry_run).await
+        {
+            Ok((acl, changed)) => {
+                if changed && !dry_run {
+                    let record =
+                        Record::new(actor, "server-acl")
+                            .room(room_id)
+                            .reason(Some(format!(
+                                "allow: {:?}, deny: {:?}",
+                                acl.allow, acl.deny
+                            )));
+                    audit.record(record).await;
+                }
+                AclResult {
+                    room_id: room_id.clone(),
+                    allow: acl.allow,
+                    deny: acl.deny,
+                    changed,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "(Room: {}) Failed to update the server ACL. Error: {:?}",
+                    room_id,
+                    e
+                );
+                AclResult {
+                    room_id: room_id.clone(),
+                    allow: vec![],
+                    deny: vec![],
+                    changed: false,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        results.push(result);
+    }
+    results
+}
+
+/// Apply a change to the server ACL of a single room.
+///
+/// Returns the resulting ACL and whether it changed.
+async fn update_acl(
+    client: &Client,
+    own_server: &ServerName,
+    room_id: &RoomId,
+    change: &AclChange,
+    dry_run: bool,
+) -> anyhow::Result<(ServerAclEventContent, bool)> {
+    // Without an ACL all servers are allowed.
+    let old = matrix::server_acl(client, room_id)
+        .await?
+        .unwrap_or_else(|| ServerAclEventContent::new(true, vec!["*".into()], vec![]));
+    let mut acl = old.clone();
+    change.apply(&mut acl);
+
+    let changed = acl.allow != old.allow || acl.deny != old.deny;
+    if !changed {
+        return Ok((acl, false));
+    }
+    if !server_allowed(&acl, own_server) {
+        bail!("change would lock out the bot's own server {}", own_server);
+    }
+    if !dry_run {
+        matrix::set_server_acl(client, room_id, acl.clone()).await?;
+    }
+
+    Ok((acl, true))
+}
+
+/// Check whether a server is allowed by a server ACL.
+fn server_allowed(acl: &ServerAclEventContent, server: &ServerName) -> bool {
+    let matches = |globs: &[String]| {
+        globs
+            .iter()
+            .filter_map(|glob| glob_to_regex(glob))
+            .any(|re| re.is_match(server.as_str()))
+    };
+    !matches(&acl.deny) && matches(&acl.allow)
+}
+
+/// Turn a glob with `*` and `?` into an anchored regular expression.
+pub fn glob_to_regex(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).ok()
+}
@@ -0,0 +1,321 @@
+//! Room provisioning from a conference manifest.
+//!
+//! A manifest describes the spaces and rooms of a conference.
+//! Provisioning reconciles the homeserver with it: missing rooms are created,
+//! names, topics, moderators and space membership are updated.
+//! Running it again only changes what drifted since.
+
+use crate::{
+    audit::{AuditLog, Record},
+    config::{self, RoomTemplate},
+    matrix::{self, PowerLevelChange},
+    moderation,
+    store::Store,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail};
+use reqwest::StatusCode;
+use ruma::{Int, RoomId, ServerName, UserId};
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The power level given to moderators.
+const MODERATOR_LEVEL: i32 = 50;
+
+/// The spaces and rooms of a conference.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    /// Spaces, created before any rooms.
+    #[serde(default)]
+    pub spaces: Vec<ManifestRoom>,
+    /// Rooms
+    #[serde(default)]
+    pub rooms: Vec<ManifestRoom>,
+}
+
+/// A space or room in the manifest.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestRoom {
+    /// The room's alias, without the leading `#` and the server name.
+    pub alias: String,
+    /// The room's name.
+    pub name: String,
+    /// The room's topic.
+    pub topic: Option<String>,
+    /// The name of the room template to create the room from.
+    pub template: Option<String>,
+    /// Users to give moderator power level (50).
+    #[serde(default)]
+    pub moderators: Vec<UserId>,
+    /// The space the room is in, by alias (like `alias` above), full alias or room ID.
+    pub space: Option<String>,
+    /// The position of the room within its space.
+    pub order: Option<String>,
+}
+
+impl Manifest {
+    /// Read a manifest from a file.
+    ///
+    /// Files ending in `.json` are read as JSON, all others as TOML.
+    pub fn load<P: AsRef<Path>>(file: P) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(&file)?;
+        let manifest = match file.as_ref().extension() {
+            Some(ext) if ext == "json" => serde_json::from_str(&content)?,
+            _ => toml::from_str(&content)?,
+        };
+        Ok(manifest)
+    }
+}
+
+/// The outcome of provisioning a single space or room.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Outcome {
+    /// The room's full alias.
+    pub alias: String,
+    /// The room's ID, unless it does not exist.
+    pub room_id: Option<RoomId>,
+    /// What differed from the manifest and was changed, or would be changed in a dry run.
+    pub changes: Vec<String>,
+    /// Why provisioning failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Reconcile the homeserver with the manifest.
+///
+/// With `dry_run` nothing is changed, the outcome only reports the drift.
+/// Newly created rooms invite the bot admins and their moderators.
+#[allow(clippy::too_many_arguments)]
+pub async fn provision(
+    client: &Client,
+    audit: &AuditLog,
+    actor: &str,
+    bot_id: &UserId,
+    admin_users: &[String],
+    templates: &BTreeMap<String, RoomTemplate>,
+    store: &Store,
+    manifest: &Manifest,
+    dry_run: bool,
+) -> Vec<Outcome> {
+    let ctx = Context {
+        client,
+        audit,
+        actor,
+        admin_users,
+        templates,
+        store,
+        server: bot_id.server_name(),
+        dry_run,
+    };
+    let spaces = manifest.spaces.iter().map(|room| (room, true));
+    let rooms = manifest.rooms.iter().map(|room| (room, false));
+
+    let mut outcomes = vec![];
+    for (room, space) in spaces.chain(rooms) {
+        let mut outcome = Outcome {
+            alias: full_alias(&room.alias, ctx.server),
+            room_id: None,
+            changes: vec![],
+            error: None,
+        };
+        if let Err(e) = reconcile(&ctx, room, space, &mut outcome).await {
+            log::error!("Failed to provision {}. Error: {:?}", outcome.alias, e);
+            outcome.error = Some(e.to_string());
+        }
+        outcomes.push(outcome);
+    }
+    outcomes
+}
+
+/// The response of `POST /provision`.
+#[derive(Deserialize)]
+struct ApiOutcomes {
+    rooms: Vec<Outcome>,
+}
+
+/// Reconcile the homeserver with the manifest through the API of the running bot.
+///
+/// The bot owns the state file, so provisioning from another process would overwrite its changes.
+pub async fn through_api(
+    addr: SocketAddr,
+    api_key: &str,
+    manifest: &Manifest,
+    dry_run: bool,
+) -> anyhow::Result<Vec<Outcome>> {
+    let mut addr = addr;
+    if addr.ip().is_unspecified() {
+        let localhost = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        };
+        addr.set_ip(localhost);
+    }
+    let url = format!("http://{}/provision", addr);
+
+    let body = json!({ "api_key": api_key, "manifest": manifest, "dry_run": dry_run });
+    let response = reqwest::Client::new()
+        .post(&url)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| anyhow!("can't reach the bot at {}, is it running? ({})", url, e))?;
+    match response.status() {
+        StatusCode::OK => Ok(response.json::<ApiOutcomes>().await?.rooms),
+        StatusCode::FORBIDDEN => bail!("the bot rejected the API secret"),
+        status => bail!("provisioning through {} failed with {}", url, status),
+    }
+}
+
+/// Everything needed to reconcile a single room.
+struct Context<'a> {
+    client: &'a Client,
+    audit: &'a AuditLog,
+    actor: &'a str,
+    admin_users: &'a [String],
+    templates: &'a BTreeMap<String, RoomTemplate>,
+    store: &'a Store,
+    /// The server rooms are created on.
+    server: &'a ServerName,
+    dry_run: bool,
+}
+
+/// Reconcile a single space or room, recording changes in the outcome.
+async fn reconcile(
+    ctx: &Context<'_>,
+    room: &ManifestRoom,
+    space: bool,
+    outcome: &mut Outcome,
+) -> anyhow::Result<()> {
+    let Context {
+        client,
+        audit,
+        actor,
+        dry_run,
+        ..
+    } = *ctx;
+
+    let room_id = match matrix::find_room(client, &outcome.alias).await? {
+        Some(room_id) => room_id,
+        None => {
+            outcome.changes.push(String::from("created"));
+            if dry_run {
+                return Ok(());
+            }
+
+            let template = config::find_template(ctx.templates, room.template.as_deref())?;
+            let mut invite = ctx
+                .admin_users
+                .iter()
+                .map(|u| UserId::try_from(&u[..]))
+                .collect::<Result<Vec<_>, _>>()?;
+            invite.extend(room.moderators.iter().cloned());
+            let room_id = matrix::create_room(
+                client,
+                &room.alias,
+                &room.name,
+                room.topic.as_deref(),
+                &invite,
+                &template,
+                space,
+            )
+            .await?;
+            let template_name = room.template.as_deref().unwrap_or(config::DEFAULT_TEMPLATE);
+            ctx.store.update(|data| {
+                data.room_templates
+                    .insert(room_id.clone(), template_name.to_string())
+            })?;
+
+            let action = if space { "create-space" } else { "create-room" };
+            let record = Record::new(actor, action)
+                .target(&outcome.alias)
+                .room(&room_id);
+            audit.record(record).await;
+            room_id
+        }
+    };
+    outcome.room_id = Some(room_id.clone());
+
+    let name = matrix::room_name(client, &room_id).await?;
+    if name.as_deref() != Some(&room.name[..]) {
+        outcome
+            .changes
+            .push(format!("name: {:?} -> {:?}", name, room.name));
+        if !dry_run {
+            matrix::set_room_name(client, &room_id, &room.name).await?;
+        }
+    }
+
+    if let Some(new_topic) = &room.topic {
+        let topic = matrix::room_topic(client, &room_id).await?;
+        if topic.as_ref() != Some(new_topic) {
+            outcome
+                .changes
+                .push(format!("topic: {:?} -> {:?}", topic, new_topic));
+            if !dry_run {
+                matrix::set_room_topic(client, &room_id, new_topic).await?;
+            }
+        }
+    }
+
+    if !room.moderators.is_empty() {
+        let levels = matrix::power_levels(client, &room_id).await?;
+        let level = Int::from(MODERATOR_LEVEL);
+        let promote = room
+            .moderators
+            .iter()
+            .filter(|user_id| moderation::user_level(&levels, user_id) < level)
+            .cloned()
+            .collect::<Vec<_>>();
+        for user_id in &promote {
+            outcome.changes.push(format!("moderator: {}", user_id));
+        }
+        if !promote.is_empty() && !dry_run {
+            let change = PowerLevelChange::set_users(&promote, level);
+            matrix::update_power_levels(client, &room_id, &change).await?;
+        }
+    }
+
+    if let Some(parent) = &room.space {
+        let parent = if parent.starts_with('#') || parent.starts_with('!') {
+            parent.clone()
+        } else {
+            full_alias(parent, ctx.server)
+        };
+        match matrix::find_room(client, &parent).await? {
+            Some(space_id) => {
+                if !matrix::is_space_child(client, &space_id, &room_id).await? {
+                    outcome.changes.push(format!("space: {}", parent));
+                    if !dry_run {
+                        matrix::add_to_space(client, &space_id, &room_id, room.order.as_deref())
+                            .await?;
+                    }
+                }
+            }
+            // The space would have been created earlier in the same run.
+            None if dry_run => outcome.changes.push(format!("space: {}", parent)),
+            None => bail!("unknown space {}", parent),
+        }
+    }
+
+    if !dry_run && !outcome.changes.is_empty() {
+        let record = Record::new(actor, "provision")
+            .target(&outcome.alias)
+            .room(&room_id)
+            .reason(Some(outcome.changes.join(", ")));
+        audit.record(record).await;
+    }
+
+    Ok(())
+}
+
+/// The full alias of a room on the given server.
+fn full_alias(alias: &str, server: &ServerName) -> String {
+    format!("#{}:{}", alias, server)
+}
@@ -0,0 +1,167 @@
+//! Conference schedule sync.
+//!
+//! Regularly fetches the sessions from the backend,
+//! keeps each room's topic on the current and next session
+//! and announces sessions when they start.
+
+use crate::{config::ScheduleConfig, matrix, strapi};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ruma::RoomId;
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+use serde::Deserialize;
+
+/// A session as listed by the backend.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Session {
+    /// The session's title.
+    pub title: String,
+    /// When the session starts.
+    pub start: DateTime<Utc>,
+    /// When the session ends, if known.
+    pub end: Option<DateTime<Utc>>,
+    /// Where the session takes place: a name mapped to a room in the configuration,
+    /// or a Matrix room ID or alias.
+    pub room: Option<String>,
+}
+
+impl Session {
+    /// Check whether the session is running at the given time.
+    ///
+    /// Sessions without an end run until the next session in the same room starts.
+    fn is_running(&self, now: DateTime<Utc>, next: Option<&Session>) -> bool {
+        let end = self.end.or_else(|| next.map(|next| next.start));
+        self.start <= now && end.is_none_or(|end| now < end)
+    }
+}
+
+/// Fetch all sessions from the backend.
+pub async fn fetch(strapi_client: &strapi::Client, endpoint: &str) -> anyhow::Result<Vec<Session>> {
+    let mut sessions: Vec<Session> = strapi::get(strapi_client, endpoint).await?;
+    sessions.sort_by_key(|session| session.start);
+    Ok(sessions)
+}
+
+/// Group sessions by their Matrix room, sorted by start.
+///
+/// Sessions without a known room are skipped.
+pub async fn by_room(
+    client: &Client,
+    config: &ScheduleConfig,
+    sessions: Vec<Session>,
+) -> BTreeMap<RoomId, Vec<Session>> {
+    let mut resolved: HashMap<String, Option<RoomId>> = HashMap::new();
+    let mut rooms: BTreeMap<RoomId, Vec<Session>> = BTreeMap::new();
+
+    for session in sessions {
+        let name = match &session.room {
+            Some(name) => name.clone(),
+            None => continue,
+        };
+        if !resolved.contains_key(&name) {
+            let room = config.rooms.get(&name).unwrap_or(&name);
+            let room_id = match matrix::real_room_id(client, room).await {
+                Ok(room_id) => Some(room_id),
+                Err(e) => {
+                    log::warn!("No room for sessions in {}. Error: {:?}", name, e);
+                    None
+                }
+            };
+            resolved.insert(name.clone(), room_id);
+        }
+        if let Some(room_id) = &resolved[&name] {
+            rooms.entry(room_id.clone()).or_default().push(session);
+        }
+    }
+
+    rooms
+}
+
+/// The current and next session in a room.
+pub fn current_and_next(
+    sessions: &[Session],
+    now: DateTime<Utc>,
+) -> (Option<&Session>, Option<&Session>) {
+    let upcoming = sessions.iter().position(|session| session.start > now);
+    let next = upcoming.map(|i| &sessions[i]);
+    let current = match upcoming {
+        Some(0) => None,
+        Some(i) => Some(&sessions[i - 1]),
+        None => sessions.last(),
+    }
+    .filter(|session| session.is_running(now, next));
+    (current, next)
+}
+
+/// The topic of a room for its current and next session.
+pub fn topic(current: Option<&Session>, next: Option<&Session>) -> Option<String> {
+    let current = current.map(|session| format!("Now: {}", session.title));
+    let next = next.map(|session| {
+        format!(
+            "Next: {} ({} UTC)",
+            session.title,
+            session.start.format("%a %H:%M")
+        )
+    });
+    match (current, next) {
+        (Some(current), Some(next)) => Some(format!("{} | {}", current, next)),
+        (current, next) => current.or(next),
+    }
+}
+
+/// Keep the rooms in sync with the schedule.
+///
+/// Runs forever, checking the schedule in the configured interval.
+/// Sessions that started before the bot was started are not announced.
+pub async fn sync(
+    client: Client,
+    strapi_client: strapi::Client,
+    config: ScheduleConfig,
+) -> anyhow::Result<()> {
+    let mut topics: HashMap<RoomId, String> = HashMap::new();
+    let mut last_check = Utc::now();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+    loop {
+        interval.tick().await;
+
+        let sessions = match fetch(&strapi_client, &config.endpoint).await {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                log::error!("Failed to fetch the schedule. Error: {:?}", e);
+                continue;
+            }
+        };
+        let rooms = by_room(&client, &config, sessions).await;
+        let now = Utc::now();
+
+        for (room_id, sessions) in &rooms {
+            let (current, next) = current_and_next(sessions, now);
+
+            if let Some(topic) = topic(current, next) {
+                if topics.get(room_id) != Some(&topic) {
+                    match matrix::set_room_topic(&client, room_id, &topic).await {
+                        Ok(()) => {
+                            topics.insert(room_id.clone(), topic);
+                        }
+                        Err(e) => log::error!("Failed to set topic of {}. Error: {:?}", room_id, e),
+                    }
+                }
+            }
+
+            let starting = sessions
+                .iter()
+                .filter(|session| last_check < session.start && session.start <= now);
+            for session in starting {
+                let msg = format!("Starting now: {}", session.title);
+                if let Err(e) = matrix::send_notice(&client, room_id, msg).await {
+                    log::error!("Failed to announce session in {}. Error: {:?}", room_id, e);
+                }
+            }
+        }
+
+        last_check = now;
+    }
+}
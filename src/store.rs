@@ -0,0 +1,142 @@
+//! Persistent bot state.
+//!
+//! Settings changed at runtime are kept in a JSON file, so they survive restarts.
+
+use crate::{announcements::Announcement, moderation::Mute};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use ruma::{Int, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+/// Everything that is persisted.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Data {
+    /// Minimum number of seconds between messages of a user, per room.
+    #[serde(default)]
+    pub slowmode: HashMap<RoomId, u64>,
+
+    /// The `events_default` power level of locked rooms from before they were locked.
+    #[serde(default)]
+    pub locked: HashMap<RoomId, Int>,
+
+    /// Direct message rooms the bot opened, per user.
+    #[serde(default)]
+    pub direct_rooms: HashMap<UserId, RoomId>,
+
+    /// The template each room was created from.
+    #[serde(default)]
+    pub room_templates: HashMap<RoomId, String>,
+
+    /// Scheduled announcements, by ID.
+    #[serde(default)]
+    pub announcements: BTreeMap<u64, Announcement>,
+
+    /// The ID of the last scheduled announcement.
+    #[serde(default)]
+    pub next_announcement_id: u64,
+
+    /// The users who redeemed each ticket code.
+    #[serde(default)]
+    pub ticket_uses: HashMap<String, Vec<UserId>>,
+
+    /// Temporary mutes, lifted once they end.
+    #[serde(default)]
+    pub mutes: Vec<Mute>,
+}
+
+impl Data {
+    /// Drop the settings of a room the bot left.
+    pub fn remove_room(&mut self, room_id: &RoomId) {
+        self.slowmode.remove(room_id);
+        self.locked.remove(room_id);
+        self.room_templates.remove(room_id);
+        self.direct_rooms
+            .retain(|_, direct_room| direct_room != room_id);
+        self.announcements
+            .retain(|_, announcement| announcement.room_id != *room_id);
+        self.mutes.retain(|mute| mute.room_id != *room_id);
+    }
+
+    /// Move the settings of a room to the room replacing it.
+    pub fn move_room(&mut self, from: &RoomId, to: &RoomId) {
+        if let Some(slowmode) = self.slowmode.remove(from) {
+            self.slowmode.insert(to.clone(), slowmode);
+        }
+        if let Some(locked) = self.locked.remove(from) {
+            self.locked.insert(to.clone(), locked);
+        }
+        if let Some(template) = self.room_templates.remove(from) {
+            self.room_templates.insert(to.clone(), template);
+        }
+        for direct_room in self.direct_rooms.values_mut() {
+            if direct_room == from {
+                *direct_room = to.clone();
+            }
+        }
+        for announcement in self.announcements.values_mut() {
+            if announcement.room_id == *from {
+                announcement.room_id = to.clone();
+            }
+        }
+        for mute in &mut self.mutes {
+            if mute.room_id == *from {
+                mute.room_id = to.clone();
+            }
+        }
+    }
+}
+
+/// A handle to the persisted state, shared by the bot and the API.
+#[derive(Clone)]
+pub struct Store {
+    file: Option<PathBuf>,
+    data: Arc<Mutex<Data>>,
+}
+
+impl Store {
+    /// Load the state from a file.
+    ///
+    /// Starts out empty if the file does not exist yet.
+    /// Without a file the state is kept in memory only.
+    pub fn load(file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let data = match &file {
+            Some(file) if file.exists() => serde_json::from_str(&fs::read_to_string(file)?)?,
+            Some(_) => Data::default(),
+            None => {
+                log::warn!("No state file configured. Runtime settings will be lost on restart.");
+                Data::default()
+            }
+        };
+
+        Ok(Store {
+            file,
+            data: Arc::new(Mutex::new(data)),
+        })
+    }
+
+    /// Read from the state.
+    pub fn read<R>(&self, f: impl FnOnce(&Data) -> R) -> R {
+        let data = self.data.lock().unwrap();
+        f(&data)
+    }
+
+    /// Change the state and write it to the file.
+    pub fn update<R>(&self, f: impl FnOnce(&mut Data) -> R) -> anyhow::Result<R> {
+        let mut data = self.data.lock().unwrap();
+        let res = f(&mut data);
+
+        if let Some(file) = &self.file {
+            // Write to a temporary file first, so a crash never leaves a half-written state behind.
+            let tmp = file.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_string_pretty(&*data)?)?;
+            fs::rename(&tmp, file)?;
+        }
+
+        Ok(res)
+    }
+}
@@ -5,7 +5,7 @@
 
 use anyhow::bail;
 use reqwest::StatusCode;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 /// A client to interact with Strapi
 #[derive(Clone)]
@@ -55,7 +55,7 @@ pub async fn login(
         password,
     };
     let response = http
-        .post(&_url(base, "auth/local"))
+        .post(_url(base, "auth/local"))
         .json(&login)
         .send()
         .await?;
@@ -82,7 +82,7 @@ pub async fn post<T: Serialize + ?Sized>(
     log::debug!("JWT: {}", client.jwt);
     let res = client
         .http
-        .post(&client.url(path))
+        .post(client.url(path))
         .bearer_auth(&client.jwt)
         .json(data)
         .send()
@@ -91,3 +91,20 @@ pub async fn post<T: Serialize + ?Sized>(
 
     Ok(())
 }
+
+/// Get data from the API with an authorized client.
+pub async fn get<T: DeserializeOwned>(client: &Client, path: &str) -> anyhow::Result<T> {
+    let res = client
+        .http
+        .get(client.url(path))
+        .bearer_auth(&client.jwt)
+        .send()
+        .await?;
+    log::debug!("Response: {:?}", res);
+    if !res.status().is_success() {
+        bail!("Failed to get {}, status: {:?}", path, res.status());
+    }
+
+    let data = res.json().await?;
+    Ok(data)
+}
@@ -0,0 +1,136 @@
+//! Room upgrades.
+//!
+//! Upgrading replaces a room with a new room on another room version.
+//! The homeserver copies most of the room's state and moves its local aliases,
+//! but the bot does not rely on that for what it manages:
+//! the power levels, server ACL, canonical alias and the room's place in spaces are carried over here.
+//! Settings in the state file move once the bot follows the old room's tombstone.
+
+use crate::{
+    audit::{AuditLog, Record},
+    matrix,
+};
+use std::convert::TryFrom;
+
+use anyhow::bail;
+use ruma::{RoomId, RoomVersionId};
+use serde_json::{json, Value as JsonValue};
+type Client = ruma_client::Client<ruma_client::http_client::HyperNativeTls>;
+
+/// State copied to the new room as it is.
+const COPIED_STATE: &[&str] = &[
+    "m.room.power_levels",
+    "m.room.server_acl",
+    "m.room.canonical_alias",
+    "m.space.parent",
+    "m.space.child",
+];
+
+/// The outcome of an upgrade.
+#[derive(Debug)]
+pub struct Upgrade {
+    /// The room replacing the upgraded room.
+    pub room_id: RoomId,
+    /// The new room's version.
+    pub version: RoomVersionId,
+    /// Settings that could not be carried over, with the reason.
+    pub failed: Vec<String>,
+}
+
+/// Check whether state event content is set, as opposed to emptied to remove the event.
+fn is_set(content: &JsonValue) -> bool {
+    content
+        .as_object()
+        .is_some_and(|content| !content.is_empty())
+}
+
+/// Upgrade a room to a room version, the server's default version unless given.
+///
+/// The upgrade is recorded in the audit log on behalf of the actor.
+/// Failing to carry over a setting does not fail the upgrade, it is reported instead.
+pub async fn upgrade(
+    client: &Client,
+    audit: &AuditLog,
+    actor: &str,
+    room_id: &RoomId,
+    version: Option<&str>,
+) -> anyhow::Result<Upgrade> {
+    let (default, available) = matrix::room_versions(client).await?;
+    let version = match version {
+        Some(version) => RoomVersionId::try_from(version)?,
+        None => default,
+    };
+    if !available.contains(&version) {
+        bail!("room version {} is not available on this server", version);
+    }
+
+    // The homeserver restricts the old room during the upgrade, so read its state first.
+    let state = matrix::room_state(client, room_id).await?;
+    let new_room = matrix::upgrade_room(client, room_id, &version).await?;
+    log::info!("(Room: {}) Upgraded to {}", room_id, new_room);
+
+    let mut failed = vec![];
+    for entry in state.iter().filter(|entry| is_set(&entry.content)) {
+        if !COPIED_STATE.contains(&&entry.typ[..]) {
+            continue;
+        }
+        let res = matrix::send_raw_state(
+            client,
+            &new_room,
+            &entry.typ,
+            &entry.state_key,
+            &entry.content,
+        )
+        .await;
+        if let Err(e) = res {
+            failed.push(format!("{} {}: {}", entry.typ, entry.state_key, e));
+        }
+
+        // Point the related spaces and rooms to the new room.
+        let linked = match &entry.typ[..] {
+            "m.space.parent" => Some("m.space.child"),
+            "m.space.child" => Some("m.space.parent"),
+            _ => None,
+        };
+        if let Some(link_type) = linked {
+            if let Err(e) = relink(client, &entry.state_key, link_type, room_id, &new_room).await {
+                failed.push(format!("{} in {}: {}", link_type, entry.state_key, e));
+            }
+        }
+    }
+    for failure in &failed {
+        log::warn!("(Room: {}) Failed to carry over {}", room_id, failure);
+    }
+
+    let record = Record::new(actor, "upgrade")
+        .target(&new_room)
+        .room(room_id)
+        .reason(Some(format!("room version {}", version)));
+    audit.record(record).await;
+
+    Ok(Upgrade {
+        room_id: new_room,
+        version,
+        failed,
+    })
+}
+
+/// Move the link from another room, a space or a space's child, to the new room.
+///
+/// Copies the other room's event about the old room to the new room and empties the old one.
+async fn relink(
+    client: &Client,
+    other: &str,
+    event_type: &str,
+    old_room: &RoomId,
+    new_room: &RoomId,
+) -> anyhow::Result<()> {
+    let other = RoomId::try_from(other)?;
+    let link = match matrix::raw_state(client, &other, event_type, old_room.as_str()).await? {
+        Some(link) if is_set(&link) => link,
+        _ => return Ok(()),
+    };
+
+    matrix::send_raw_state(client, &other, event_type, new_room.as_str(), &link).await?;
+    matrix::send_raw_state(client, &other, event_type, old_room.as_str(), &json!({})).await
+}